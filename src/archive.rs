@@ -0,0 +1,119 @@
+//! Exportación e importación del historial.
+//!
+//! El archivo es un tar sin cifrar con la misma estructura que el historial
+//! en disco: `manifest.jsonl`, con una entrada por línea, más las imágenes
+//! en `images/<id>.png` y el resto de formatos en `formats/<id>-<n>`. Así
+//! se puede llevar a otro equipo, o abrirse con cualquier programa.
+
+use crate::history::{self, HistoryItem, StoredEntry};
+use crate::{mime, ClipboardContent};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+const MANIFEST: &str = "manifest.jsonl";
+const HOUR: i64 = 60 * 60;
+
+/// Qué exportar desde los ajustes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Range {
+    All,
+    Pinned,
+    Day,
+    Week,
+    Month,
+}
+
+impl Range {
+    pub const ALL: [Range; 5] = [
+        Range::All,
+        Range::Pinned,
+        Range::Day,
+        Range::Week,
+        Range::Month,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Range::All => "Todo el historial",
+            Range::Pinned => "Solo las fijadas",
+            Range::Day => "Últimas 24 horas",
+            Range::Week => "Última semana",
+            Range::Month => "Último mes",
+        }
+    }
+
+    pub fn includes(self, item: &HistoryItem) -> bool {
+        let max_age = match self {
+            Range::All => return true,
+            Range::Pinned => return item.pinned,
+            Range::Day => 24 * HOUR,
+            Range::Week => 7 * 24 * HOUR,
+            Range::Month => 30 * 24 * HOUR,
+        };
+        history::now_unix() - item.created <= max_age
+    }
+}
+
+/// Escribe `items` en un archivo nuevo en `path`.
+pub fn export(path: &Path, items: &[&HistoryItem]) -> Result<(), Box<dyn Error>> {
+    let mut manifest = Vec::new();
+    for item in items {
+        serde_json::to_writer(&mut manifest, &StoredEntry::from(*item))?;
+        manifest.push(b'\n');
+    }
+
+    let mut tar = tar::Builder::new(fs::File::create(path)?);
+    append(&mut tar, MANIFEST, &manifest)?;
+    for item in items {
+        if let ClipboardContent::Image { raw, .. } = &item.content {
+            let png = mime::encode_png(raw).ok_or("no se pudo codificar una imagen")?;
+            append(&mut tar, &history::image_name(item.id), &png)?;
+        }
+        for (n, f) in item.formats.iter().enumerate() {
+            append(&mut tar, &history::format_name(item.id, n), &f.data)?;
+        }
+    }
+    tar.into_inner()?.sync_all()?;
+    Ok(())
+}
+
+fn append(tar: &mut tar::Builder<fs::File>, name: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(history::now_unix() as u64);
+    header.set_cksum();
+    tar.append_data(&mut header, name, data)?;
+    Ok(())
+}
+
+/// Lee las entradas de un archivo, en el orden en que se exportaron. Los
+/// ids son los del equipo de origen; quien las añada debe darles otros.
+pub fn import(path: &Path) -> Result<Vec<HistoryItem>, Box<dyn Error>> {
+    let mut files: HashMap<String, Vec<u8>> = HashMap::new();
+    let mut archive = tar::Archive::new(fs::File::open(path)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        files.insert(name, data);
+    }
+
+    let manifest = files
+        .get(MANIFEST)
+        .ok_or("no es un archivo de klipBored (falta manifest.jsonl)")?;
+    let mut items = Vec::new();
+    for line in manifest.split(|&b| b == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        let entry: StoredEntry = serde_json::from_slice(line)?;
+        // Sin su imagen la entrada no sirve de nada; se salta
+        items.extend(entry.into_item(|name| files.get(name).cloned()));
+    }
+    Ok(items)
+}
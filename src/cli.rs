@@ -0,0 +1,538 @@
+//! Órdenes de la línea de comandos.
+//!
+//! `klipBored <orden>` no abre otra ventana: GApplication pasa los
+//! argumentos a la instancia que ya está en marcha (o la arranca), que es
+//! quien tiene el historial, y le devuelve la salida y el código de salida.
+//! Las órdenes que leen o cambian el historial no la arrancan: sin ella
+//! fallan (ver `needs_instance`). `paste` y `help` se resuelven en el propio
+//! proceso; `paste`, para poder escribir datos binarios en la salida
+//! estándar.
+//!
+//! `copy` y `paste` aceptan las opciones de `wl-copy`/`wl-paste` y las de
+//! `xclip` que usan Neovim, tmux y los scripts habituales.
+
+use gtk::{gio, glib};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+pub const USAGE: &str = "\
+Uso: klipBored [ORDEN]
+
+Sin orden, abre o cierra el panel.
+
+Órdenes:
+  list [--json] [--limit N]  Lista el historial, de la entrada 1 en adelante
+  get N                      Escribe el contenido de la entrada N
+  copy N                     Pone la entrada N en el portapapeles
+  copy [-t TIPO] [-p] [-n]   Copia la entrada estándar y la guarda en el historial
+  paste [-t TIPO] [-p] [-n]  Escribe el portapapeles en la salida estándar
+  paste -l                   Lista los tipos en que se puede pegar
+  delete N                   Borra la entrada N
+  clear                      Borra el historial salvo las entradas fijadas
+  export FICHERO [N...] [--since FECHA] [--until FECHA]
+                             Exporta todo o las entradas N, copiadas entre esas
+                             fechas (AAAA-MM-DD), a un archivo .tar sin cifrar
+  import FICHERO             Añade al historial lo que no esté ya
+  pause                      Deja de guardar lo que se copie
+  resume                     Vuelve a guardar lo que se copie
+  show | hide | toggle       Muestra, oculta o alterna el panel
+  reset [--purge]            Quita el atajo y el inicio automático y devuelve
+                             a GNOME los atajos que se le quitaron; con --purge
+                             borra también el historial, su clave y los ajustes
+  help                       Muestra esta ayuda
+
+Opciones de copy y paste:
+  -t, --type TIPO            Tipo MIME (también `text` o `TARGETS` como en xclip)
+  -p, --primary              La selección primaria en vez del portapapeles
+  -n                         copy: quita el salto de línea final;
+                             paste: no lo añade
+  -selection clipboard|primary, -i, -o  Como en xclip (paste no añade
+                             el salto de línea)
+  -r, -rmlastnl              copy: como -n
+
+copy acepta y no hace caso de --foreground, --paste-once, -quiet, -silent,
+-loops N y -filter, que en wl-copy y xclip cambian cómo se queda esperando.
+";
+
+pub const EXIT_OK: u8 = 0;
+/// La orden no se pudo hacer: no existe la entrada, etc.
+pub const EXIT_FAILURE: u8 = 1;
+/// Orden o argumentos mal escritos.
+pub const EXIT_USAGE: u8 = 2;
+
+/// Una orden. Las entradas se numeran desde 1 en el orden del panel.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Toggle,
+    Show,
+    Hide,
+    List {
+        json: bool,
+        limit: Option<usize>,
+    },
+    Get(usize),
+    Copy(usize),
+    /// `copy` sin número: lo que llegue por la entrada estándar.
+    CopyInput(CopyOptions),
+    Paste(PasteOptions),
+    Delete(usize),
+    Clear,
+    /// Las entradas `numbers` (o todas) copiadas desde `since` y antes de
+    /// `until` (segundos Unix).
+    Export {
+        path: String,
+        numbers: Vec<usize>,
+        since: Option<i64>,
+        until: Option<i64>,
+    },
+    Import(String),
+    Pause(bool),
+    /// Deshacer la integración con el escritorio; con `purge`, borrarlo todo.
+    Reset {
+        purge: bool,
+    },
+    Help,
+}
+
+/// Opciones de `copy` al leer de la entrada estándar (`wl-copy`, `xclip -i`).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CopyOptions {
+    /// Si no se da, se adivina por el contenido.
+    pub mime: Option<String>,
+    pub primary: bool,
+    pub trim_newline: bool,
+}
+
+/// Opciones de `paste` (`wl-paste`, `xclip -o`).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PasteOptions {
+    pub mime: Option<String>,
+    pub primary: bool,
+    pub list_types: bool,
+    pub no_newline: bool,
+    /// Llamada con las opciones de `xclip`, que escribe el contenido tal
+    /// cual; `wl-paste` añade un salto de línea al texto.
+    pub xclip: bool,
+}
+
+/// Una orden a la espera de respuesta. `gio::ApplicationCommandLine` no
+/// puede salir del hilo principal, así que se queda en `Pending` y al modelo
+/// le llega esto.
+#[derive(Debug, Clone, Copy)]
+pub struct Request(u64);
+
+/// Las órdenes que aún no se han respondido. Al soltar la última referencia
+/// a la línea de órdenes, GApplication devuelve el código de salida a quien
+/// la lanzó.
+#[derive(Clone, Default)]
+pub struct Pending(Rc<RefCell<(u64, HashMap<u64, gio::ApplicationCommandLine>)>>);
+
+impl Pending {
+    pub fn add(&self, cmdline: gio::ApplicationCommandLine) -> Request {
+        let (next_id, commands) = &mut *self.0.borrow_mut();
+        let id = *next_id;
+        *next_id = id.wrapping_add(1);
+        commands.insert(id, cmdline);
+        Request(id)
+    }
+
+    pub fn get(&self, request: Request) -> Option<gio::ApplicationCommandLine> {
+        self.0.borrow().1.get(&request.0).cloned()
+    }
+
+    /// Saca la orden para responderla.
+    pub fn take(&self, request: Request) -> Option<gio::ApplicationCommandLine> {
+        self.0.borrow_mut().1.remove(&request.0)
+    }
+}
+
+impl Command {
+    /// Interpreta los argumentos, sin el nombre del programa.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let Some((name, rest)) = args.split_first() else {
+            return Ok(Command::Toggle);
+        };
+        let command = match name.as_str() {
+            "toggle" => Command::Toggle,
+            "show" => Command::Show,
+            "hide" => Command::Hide,
+            "list" => return parse_list(rest),
+            "get" => Command::Get(parse_index(rest)?),
+            "copy" => return parse_copy(rest),
+            "paste" => return parse_paste(rest),
+            "delete" => Command::Delete(parse_index(rest)?),
+            "clear" => Command::Clear,
+            "export" => return parse_export(rest),
+            "import" => match rest {
+                [path] => return Ok(Command::Import(path.clone())),
+                [] => return Err("falta el fichero que importar".to_string()),
+                _ => return Err("solo se importa un fichero cada vez".to_string()),
+            },
+            "pause" => Command::Pause(true),
+            "resume" => Command::Pause(false),
+            "reset" | "--reset" => match rest {
+                [] => Command::Reset { purge: false },
+                [flag] if flag == "--purge" => return Ok(Command::Reset { purge: true }),
+                _ => return Err(format!("{} solo admite --purge", name)),
+            },
+            "help" | "--help" | "-h" => Command::Help,
+            other => return Err(format!("orden desconocida: {}", other)),
+        };
+        let takes_index = matches!(command, Command::Get(_) | Command::Delete(_));
+        if !takes_index && !rest.is_empty() {
+            return Err(format!("{} no lleva argumentos", name));
+        }
+        Ok(command)
+    }
+
+    /// Si necesita el historial cargado (y por tanto desbloqueado).
+    pub fn needs_history(&self) -> bool {
+        !matches!(
+            self,
+            Command::Toggle
+                | Command::Show
+                | Command::Hide
+                | Command::Paste(_)
+                | Command::Pause(_)
+                | Command::Reset { .. }
+                | Command::Help
+        )
+    }
+
+    /// Si solo tiene sentido con klipBored ya en marcha: arrancarlo para
+    /// esto dejaría la orden esperando a que se cierre.
+    pub fn needs_instance(&self) -> bool {
+        match self {
+            // Quien copia se queda sirviendo el portapapeles, como `wl-copy`
+            Command::CopyInput(_) => false,
+            Command::Pause(_) => true,
+            command => command.needs_history(),
+        }
+    }
+}
+
+fn parse_list(args: &[String]) -> Result<Command, String> {
+    let mut json = false;
+    let mut limit = None;
+    let args = split_long(args);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--limit" => limit = Some(parse_number(&value(&mut args, arg)?)?),
+            other => return Err(format!("opción desconocida: {}", other)),
+        }
+    }
+    Ok(Command::List { json, limit })
+}
+
+/// `copy N` recupera una entrada; con opciones o sin nada, lee la entrada
+/// estándar.
+fn parse_copy(args: &[String]) -> Result<Command, String> {
+    if let [n] = args {
+        if n.bytes().all(|b| b.is_ascii_digit()) {
+            return Ok(Command::Copy(parse_number(n)?));
+        }
+    }
+    let mut opts = CopyOptions::default();
+    let args = split_long(args);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-t" | "--type" | "-target" => opts.mime = Some(mime_alias(value(&mut args, arg)?)),
+            "-p" | "--primary" => opts.primary = true,
+            "-selection" | "-sel" => opts.primary = is_primary(&value(&mut args, arg)?)?,
+            "-n" | "--trim-newline" | "-r" | "-rmlastnl" => opts.trim_newline = true,
+            // Cómo se queda sirviendo el portapapeles: aquí lo hace la instancia
+            "-i" | "-in" | "--foreground" | "-o" | "--paste-once" | "-quiet" | "-silent" | "-f"
+            | "-filter" => {}
+            "-loops" => {
+                value(&mut args, arg)?;
+            }
+            other => return Err(format!("opción desconocida: {}", other)),
+        }
+    }
+    Ok(Command::CopyInput(opts))
+}
+
+fn parse_paste(args: &[String]) -> Result<Command, String> {
+    let mut opts = PasteOptions::default();
+    let args = split_long(args);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-t" | "--type" | "-target" => {
+                opts.xclip |= arg == "-target";
+                match value(&mut args, arg)?.as_str() {
+                    // `xclip -o -t TARGETS` lista los tipos
+                    "TARGETS" => opts.list_types = true,
+                    mime => opts.mime = Some(mime_alias(mime.to_string())),
+                }
+            }
+            "-l" | "--list-types" => opts.list_types = true,
+            "-p" | "--primary" => opts.primary = true,
+            "-selection" | "-sel" => {
+                opts.xclip = true;
+                opts.primary = is_primary(&value(&mut args, arg)?)?;
+            }
+            "-n" | "--no-newline" => opts.no_newline = true,
+            "-o" | "-out" => opts.xclip = true,
+            "-quiet" | "-silent" => {}
+            other => return Err(format!("opción desconocida: {}", other)),
+        }
+    }
+    Ok(Command::Paste(opts))
+}
+
+fn parse_export(args: &[String]) -> Result<Command, String> {
+    let mut path = None;
+    let mut numbers = Vec::new();
+    let mut since = None;
+    let mut until = None;
+    let args = split_long(args);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--since" => since = Some(parse_date(&value(&mut args, arg)?, 0)?),
+            // Hasta el final de ese día
+            "--until" => until = Some(parse_date(&value(&mut args, arg)?, 1)?),
+            other if other.starts_with('-') => {
+                return Err(format!("opción desconocida: {}", other))
+            }
+            other if path.is_none() => path = Some(other.to_string()),
+            other => numbers.push(parse_number(other)?),
+        }
+    }
+    let path = path.ok_or("falta el fichero al que exportar")?;
+    Ok(Command::Export {
+        path,
+        numbers,
+        since,
+        until,
+    })
+}
+
+/// Comienzo del día `AAAA-MM-DD` más `days` días, en hora local.
+fn parse_date(s: &str, days: i32) -> Result<i64, String> {
+    let invalid = || format!("fecha no válida (AAAA-MM-DD): {}", s);
+    let parts: Vec<i32> = s
+        .split('-')
+        .map(|part| part.parse().map_err(|_| invalid()))
+        .collect::<Result<_, _>>()?;
+    let [year, month, day] = parts[..] else {
+        return Err(invalid());
+    };
+    glib::DateTime::from_local(year, month, day, 0, 0, 0.0)
+        .and_then(|date| date.add_days(days))
+        .map(|date| date.to_unix())
+        .map_err(|_| invalid())
+}
+
+/// Separa `--opcion=valor` en dos argumentos.
+fn split_long(args: &[String]) -> Vec<String> {
+    args.iter()
+        .flat_map(|arg| match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => {
+                vec![name.to_string(), value.to_string()]
+            }
+            _ => vec![arg.clone()],
+        })
+        .collect()
+}
+
+fn value<'a>(args: &mut impl Iterator<Item = &'a String>, flag: &str) -> Result<String, String> {
+    args.next()
+        .cloned()
+        .ok_or_else(|| format!("falta el valor de {}", flag))
+}
+
+/// Los nombres de tipo de X11 que usa `xclip` para el texto.
+fn mime_alias(mime: String) -> String {
+    match mime.as_str() {
+        "text" | "TEXT" | "STRING" | "UTF8_STRING" => "text/plain;charset=utf-8".to_string(),
+        _ => mime,
+    }
+}
+
+/// El argumento de `-selection` de `xclip`: basta con la inicial.
+fn is_primary(selection: &str) -> Result<bool, String> {
+    match selection {
+        "c" | "clip" | "clipboard" => Ok(false),
+        "p" | "primary" => Ok(true),
+        other => Err(format!("selección no admitida: {}", other)),
+    }
+}
+
+fn parse_index(args: &[String]) -> Result<usize, String> {
+    match args {
+        [n] => parse_number(n),
+        [] => Err("falta el número de la entrada".to_string()),
+        _ => Err("solo se admite un número de entrada".to_string()),
+    }
+}
+
+fn parse_number(s: &str) -> Result<usize, String> {
+    s.parse()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or_else(|| format!("número no válido: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        Command::parse(&args)
+    }
+
+    #[test]
+    fn history_commands_need_a_running_instance() {
+        for args in [
+            &["list"][..],
+            &["copy", "2"],
+            &["delete", "1"],
+            &["clear"],
+            &["pause"],
+        ] {
+            assert!(parse(args).unwrap().needs_instance(), "{:?}", args);
+        }
+        for args in [&[][..], &["show"], &["copy", "-n"], &["reset"], &["help"]] {
+            assert!(!parse(args).unwrap().needs_instance(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn without_arguments_toggles() {
+        assert_eq!(parse(&[]), Ok(Command::Toggle));
+    }
+
+    #[test]
+    fn copy_with_a_number_recovers_an_entry() {
+        assert_eq!(parse(&["copy", "5"]), Ok(Command::Copy(5)));
+        assert!(parse(&["copy", "0"]).is_err());
+    }
+
+    #[test]
+    fn copy_without_a_number_reads_stdin() {
+        assert_eq!(
+            parse(&["copy"]),
+            Ok(Command::CopyInput(CopyOptions::default()))
+        );
+        assert_eq!(
+            parse(&["copy", "-n", "-t", "text/html"]),
+            Ok(Command::CopyInput(CopyOptions {
+                mime: Some("text/html".to_string()),
+                trim_newline: true,
+                ..CopyOptions::default()
+            }))
+        );
+    }
+
+    #[test]
+    fn xclip_selection_takes_the_initial() {
+        let Ok(Command::CopyInput(opts)) = parse(&["copy", "-selection", "p"]) else {
+            panic!("no es copy");
+        };
+        assert!(opts.primary);
+        let Ok(Command::CopyInput(opts)) = parse(&["copy", "-selection", "clipboard"]) else {
+            panic!("no es copy");
+        };
+        assert!(!opts.primary);
+        assert!(parse(&["copy", "-selection", "secondary"]).is_err());
+    }
+
+    #[test]
+    fn xclip_target_names_are_text() {
+        let Ok(Command::Paste(opts)) = parse(&["paste", "-o", "-target", "UTF8_STRING"]) else {
+            panic!("no es paste");
+        };
+        assert_eq!(opts.mime.as_deref(), Some("text/plain;charset=utf-8"));
+        let Ok(Command::Paste(opts)) = parse(&["paste", "-o", "-t", "TARGETS"]) else {
+            panic!("no es paste");
+        };
+        assert!(opts.list_types);
+    }
+
+    #[test]
+    fn paste_remembers_the_xclip_style() {
+        let Ok(Command::Paste(opts)) = parse(&["paste", "-o", "-selection", "clipboard"]) else {
+            panic!("no es paste");
+        };
+        assert!(opts.xclip);
+        let Ok(Command::Paste(opts)) = parse(&["paste", "-p", "-t", "text/plain"]) else {
+            panic!("no es paste");
+        };
+        assert!(!opts.xclip);
+    }
+
+    #[test]
+    fn neovim_clipboard_commands_are_accepted() {
+        let copy = |primary| {
+            Ok(Command::CopyInput(CopyOptions {
+                mime: Some("text/plain".to_string()),
+                primary,
+                ..CopyOptions::default()
+            }))
+        };
+        assert_eq!(
+            parse(&["copy", "--foreground", "--type", "text/plain"]),
+            copy(false)
+        );
+        assert_eq!(
+            parse(&["copy", "--foreground", "--primary", "--type", "text/plain"]),
+            copy(true)
+        );
+        let xclip = |args: &[&str]| {
+            let Ok(Command::CopyInput(opts)) = parse(args) else {
+                panic!("no es copy: {:?}", args);
+            };
+            opts
+        };
+        assert!(!xclip(&["copy", "-quiet", "-i", "-selection", "clipboard"]).primary);
+        assert!(xclip(&["copy", "-quiet", "-i", "-selection", "primary"]).primary);
+        assert!(xclip(&["copy", "-silent", "-loops", "1", "-rmlastnl"]).trim_newline);
+        assert!(parse(&["copy", "-loops"]).is_err());
+
+        let Ok(Command::Paste(opts)) = parse(&["paste", "--no-newline"]) else {
+            panic!("no es paste");
+        };
+        assert!(opts.no_newline && !opts.primary);
+        let Ok(Command::Paste(opts)) = parse(&["paste", "-o", "-selection", "primary"]) else {
+            panic!("no es paste");
+        };
+        assert!(opts.xclip && opts.primary);
+    }
+
+    #[test]
+    fn long_options_take_their_value_after_equals() {
+        let expected = Ok(Command::List {
+            json: false,
+            limit: Some(3),
+        });
+        assert_eq!(parse(&["list", "--limit=3"]), expected);
+        assert_eq!(parse(&["list", "--limit", "3"]), expected);
+        assert!(parse(&["list", "--limit"]).is_err());
+        assert!(parse(&["list", "--limit=0"]).is_err());
+    }
+
+    #[test]
+    fn reset_only_takes_purge() {
+        assert_eq!(parse(&["reset"]), Ok(Command::Reset { purge: false }));
+        assert_eq!(
+            parse(&["--reset", "--purge"]),
+            Ok(Command::Reset { purge: true })
+        );
+        assert!(parse(&["reset", "--all"]).is_err());
+    }
+
+    #[test]
+    fn unexpected_arguments_are_errors() {
+        assert!(parse(&["frobnicate"]).is_err());
+        assert!(parse(&["clear", "1"]).is_err());
+        assert!(parse(&["get"]).is_err());
+        assert!(parse(&["delete", "1", "2"]).is_err());
+    }
+}
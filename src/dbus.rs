@@ -0,0 +1,135 @@
+//! Interfaz D-Bus del historial (`io.github.klipbored.History`).
+//!
+//! Se exporta en el mismo objeto que `adw::Application` registra en el bus
+//! de sesión. La descripción de cada método está en `dbus.xml`. Las
+//! llamadas se pasan al modelo, que es quien responde.
+
+use gtk::gio;
+use gtk::glib::{self, variant::ToVariant};
+use gtk::prelude::*;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+const INTERFACE_XML: &str = include_str!("dbus.xml");
+pub const INTERFACE: &str = "io.github.klipbored.History";
+const NOT_FOUND: &str = "io.github.klipbored.Error.NotFound";
+
+/// Llamada a uno de los métodos de la interfaz.
+#[derive(Debug)]
+pub enum Call {
+    List,
+    Get(u64, String),
+    Copy(u64),
+    Delete(u64),
+    Clear,
+    Pin(u64, bool),
+    Pause(bool),
+}
+
+impl Call {
+    fn parse(method: &str, params: &glib::Variant) -> Option<Self> {
+        Some(match method {
+            "List" => Call::List,
+            "Get" => {
+                let (id, mime) = params.get()?;
+                Call::Get(id, mime)
+            }
+            "Copy" => Call::Copy(params.get::<(u64,)>()?.0),
+            "Delete" => Call::Delete(params.get::<(u64,)>()?.0),
+            "Clear" => Call::Clear,
+            "Pin" => {
+                let (id, pinned) = params.get()?;
+                Call::Pin(id, pinned)
+            }
+            "Pause" => Call::Pause(params.get::<(bool,)>()?.0),
+            _ => return None,
+        })
+    }
+}
+
+/// Una llamada a la espera de respuesta. La invocación no puede salir del
+/// hilo principal, así que se queda en `Bus` y al modelo le llega esto.
+#[derive(Debug, Clone, Copy)]
+pub struct Request(u64);
+
+/// El objeto exportado, para emitir las señales y responder a las
+/// llamadas. Sin bus no hace nada.
+#[derive(Default)]
+pub struct Bus {
+    object: Option<(gio::DBusConnection, String)>,
+    pending: Rc<RefCell<HashMap<u64, gio::DBusMethodInvocation>>>,
+}
+
+impl Bus {
+    /// Exporta la interfaz en el objeto de `app`. Cada llamada se entrega
+    /// a `on_call`; la respuesta se da con `reply`.
+    pub fn export(
+        app: &impl IsA<gio::Application>,
+        on_call: impl Fn(Call, Request) + 'static,
+    ) -> Self {
+        let (Some(conn), Some(path)) = (app.dbus_connection(), app.dbus_object_path()) else {
+            return Bus::default();
+        };
+        let pending: Rc<RefCell<HashMap<u64, gio::DBusMethodInvocation>>> = Rc::default();
+        let pending_calls = pending.clone();
+        let next_id = Cell::new(0u64);
+        let res = gio::DBusNodeInfo::for_xml(INTERFACE_XML).and_then(|node| {
+            let info = node
+                .lookup_interface(INTERFACE)
+                .expect("interfaz en dbus.xml");
+            conn.register_object(&path, &info)
+                .method_call(move |_, _, _, _, method, params, invocation| {
+                    match Call::parse(method, &params) {
+                        Some(call) => {
+                            let id = next_id.get();
+                            next_id.set(id.wrapping_add(1));
+                            pending_calls.borrow_mut().insert(id, invocation);
+                            on_call(call, Request(id));
+                        }
+                        None => invocation.return_dbus_error(
+                            "org.freedesktop.DBus.Error.InvalidArgs",
+                            "argumentos no válidos",
+                        ),
+                    }
+                })
+                .build()
+        });
+        match res {
+            Ok(_) => Bus {
+                object: Some((conn, path.to_string())),
+                pending,
+            },
+            Err(e) => {
+                eprintln!("klipBored: no se pudo exportar la interfaz D-Bus: {}", e);
+                Bus::default()
+            }
+        }
+    }
+
+    /// Responde a `request` con lo que devuelve el método, o con un error
+    /// `NOT_FOUND`.
+    pub fn reply(&self, request: Request, reply: Result<Option<glib::Variant>, String>) {
+        let Some(invocation) = self.pending.borrow_mut().remove(&request.0) else {
+            return;
+        };
+        match reply {
+            Ok(value) => invocation.return_value(value.as_ref()),
+            Err(message) => invocation.return_dbus_error(NOT_FOUND, &message),
+        }
+    }
+
+    pub fn item_added(&self, id: u64) {
+        self.emit("ItemAdded", id);
+    }
+
+    pub fn item_removed(&self, id: u64) {
+        self.emit("ItemRemoved", id);
+    }
+
+    fn emit(&self, signal: &str, id: u64) {
+        if let Some((conn, path)) = &self.object {
+            let _ = conn.emit_signal(None, path, INTERFACE, signal, Some(&(id,).to_variant()));
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! Atajo global a través del portal `org.freedesktop.portal.GlobalShortcuts`.
+//!
+//! Es lo que hay en KDE, Hyprland, Sway y el resto de escritorios cuyo
+//! portal lo implementa. A diferencia del atajo personalizado de GNOME, no
+//! lanza ningún programa: el portal avisa con la señal `Activated` mientras
+//! la sesión siga abierta, así que se registra en cada arranque.
+
+use crate::portal::{self, Portal};
+use gtk::glib::{self, variant::ToVariant};
+use gtk::{gdk, gio};
+use std::error::Error;
+
+const INTERFACE: &str = "org.freedesktop.portal.GlobalShortcuts";
+const SHORTCUT_ID: &str = "toggle";
+
+/// Si el portal del escritorio tiene atajos globales.
+pub fn available() -> bool {
+    Portal::with(|portal| {
+        portal.call(
+            "org.freedesktop.DBus.Properties",
+            "Get",
+            &(INTERFACE, "version").to_variant(),
+        )?;
+        Ok(())
+    })
+    .is_ok()
+}
+
+/// Abre una sesión con `binding` como atajo preferido, cerrando antes
+/// `old`. Devuelve la sesión y el atajo que ha quedado, que el usuario
+/// puede haber cambiado en el diálogo del portal. Bloquea hasta que el
+/// usuario responde: no se debe llamar desde el hilo de la interfaz.
+pub fn bind(binding: &str, old: Option<&str>) -> Result<(String, String), Box<dyn Error>> {
+    let preferred = trigger(binding)?;
+    Portal::with(|portal| {
+        if let Some(old) = old {
+            close(portal, old);
+        }
+        let results = portal.request(INTERFACE, "CreateSession", |token| {
+            glib::Variant::tuple_from_iter([portal::options(&[
+                ("handle_token", token.to_variant()),
+                ("session_handle_token", Portal::token().to_variant()),
+            ])])
+        })?;
+        let session: String = results
+            .lookup("session_handle")?
+            .ok_or("el portal no devolvió la sesión")?;
+        let session_path = portal::object_path(&session)?;
+
+        let shortcut = glib::Variant::tuple_from_iter([
+            SHORTCUT_ID.to_variant(),
+            portal::options(&[
+                ("description", "Abrir o cerrar klipBored".to_variant()),
+                ("preferred_trigger", preferred.to_variant()),
+            ]),
+        ]);
+        let shortcuts = glib::Variant::array_from_iter_with_type(
+            glib::VariantTy::new("(sa{sv})").expect("tipo D-Bus"),
+            [shortcut],
+        );
+        let results = portal.request(INTERFACE, "BindShortcuts", |token| {
+            glib::Variant::tuple_from_iter([
+                session_path.to_variant(),
+                shortcuts.clone(),
+                "".to_variant(),
+                portal::options(&[("handle_token", token.to_variant())]),
+            ])
+        })?;
+
+        // a(sa{sv}): el atajo con el que ha quedado, o nada si el usuario
+        // no le asignó ninguno
+        let bound = results
+            .lookup_value("shortcuts", None)
+            .and_then(|shortcuts| shortcuts.iter().next())
+            .and_then(|shortcut| {
+                glib::VariantDict::new(Some(&shortcut.child_value(1)))
+                    .lookup::<String>("trigger_description")
+                    .ok()
+                    .flatten()
+            });
+        match bound {
+            Some(description) => Ok((session, description)),
+            None => {
+                close(portal, &session);
+                Err("no se asignó ningún atajo".into())
+            }
+        }
+    })
+}
+
+/// Llama a `on_activated` cada vez que se pulse el atajo. Deja de hacerlo
+/// al soltar lo que devuelve.
+pub fn connect_activated(on_activated: impl Fn() + 'static) -> Option<gio::SignalSubscription> {
+    let conn = gio::bus_get_sync(gio::BusType::Session, gio::Cancellable::NONE).ok()?;
+    Some(conn.subscribe_to_signal(
+        Some(portal::DESTINATION),
+        Some(INTERFACE),
+        Some("Activated"),
+        Some(portal::OBJECT_PATH),
+        None,
+        gio::DBusSignalFlags::NONE,
+        move |signal| {
+            // (o session_handle, s shortcut_id, t timestamp, a{sv} options)
+            if signal.parameters.child_value(1).str() == Some(SHORTCUT_ID) {
+                on_activated();
+            }
+        },
+    ))
+}
+
+/// Cierra la sesión: el atajo deja de avisar.
+pub fn unbind(session: &str) {
+    let _ = Portal::with(|portal| {
+        close(portal, session);
+        Ok(())
+    });
+}
+
+fn close(portal: &Portal, session: &str) {
+    let _ = portal.conn.call_sync(
+        Some(portal::DESTINATION),
+        session,
+        "org.freedesktop.portal.Session",
+        "Close",
+        None,
+        None,
+        gio::DBusCallFlags::NONE,
+        -1,
+        gio::Cancellable::NONE,
+    );
+}
+
+/// `<Control><Shift>v` tal y como lo piden los portales: `CTRL+SHIFT+v`.
+fn trigger(binding: &str) -> Result<String, Box<dyn Error>> {
+    let (key, mods) = gtk::accelerator_parse(binding).ok_or("atajo no válido")?;
+    let mut parts: Vec<String> = [
+        (gdk::ModifierType::CONTROL_MASK, "CTRL"),
+        (gdk::ModifierType::ALT_MASK, "ALT"),
+        (gdk::ModifierType::SHIFT_MASK, "SHIFT"),
+        (gdk::ModifierType::SUPER_MASK, "LOGO"),
+    ]
+    .into_iter()
+    .filter(|(mask, _)| mods.contains(*mask))
+    .map(|(_, name)| name.to_string())
+    .collect();
+    parts.push(key.name().ok_or("tecla sin nombre")?.to_string());
+    Ok(parts.join("+"))
+}
@@ -0,0 +1,600 @@
+use crate::mime::MimeData;
+use crate::paste::PasteKeys;
+use crate::vault::{self, Cipher, NewKey};
+use crate::watcher::Selection;
+use crate::{compact_preview, content_hash, raw_to_texture, ClipboardContent, ImageDataOwned};
+use gtk::glib;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const INDEX_FILE: &str = "history.json";
+const IMAGES_DIR: &str = "images";
+const FORMATS_DIR: &str = "formats";
+const SESSION_FILE: &str = "session";
+// Texto conocido cifrado con la clave, para saber si una clave es la buena
+const CHECK_FILE: &str = "key-check";
+const CHECK_PLAIN: &[u8] = b"klipBored";
+// Las imágenes copiadas así ya tienen su PNG entre los formatos
+const PNG_MIME: &str = "image/png";
+
+pub fn data_dir() -> PathBuf {
+    glib::user_data_dir().join("klipBored")
+}
+
+pub fn now_unix() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Cuándo caduca una entrada, si se ha cambiado a mano.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Expiry {
+    /// Según la antigüedad máxima de los ajustes.
+    #[default]
+    Default,
+    Never,
+    /// En este instante (segundos Unix).
+    At(i64),
+}
+
+/// Una entrada del historial tal y como la maneja el modelo: el contenido
+/// más los metadatos que necesitamos para guardarla y recuperarla.
+#[derive(Debug, Clone)]
+pub struct HistoryItem {
+    pub id: u64,
+    pub created: i64,
+    pub source: Selection,
+    pub content: ClipboardContent,
+    /// Representaciones MIME originales (HTML, RTF, PNG...) además de `content`.
+    pub formats: Vec<MimeData>,
+    /// Veces que se ha copiado este mismo contenido.
+    pub times_copied: u32,
+    /// Las fijadas van arriba y no se descartan nunca.
+    pub pinned: bool,
+    /// Combinación con la que se pega al recuperarla con el pegado automático.
+    pub paste_keys: PasteKeys,
+    /// Parece un secreto: se guarda, pero no se enseña en la lista.
+    pub sensitive: bool,
+    pub expiry: Expiry,
+    /// `content_hash(&content)`, para buscar duplicados sin recalcularlo.
+    pub hash: u64,
+}
+
+impl HistoryItem {
+    /// Bytes que ocupa en memoria: el texto o la imagen RGBA más los
+    /// formatos adicionales.
+    pub fn size(&self) -> u64 {
+        let content = match &self.content {
+            ClipboardContent::Text { full, .. } => full.len(),
+            ClipboardContent::Image { raw, .. } => raw.data.len(),
+            ClipboardContent::Files { uris, .. } => uris.iter().map(String::len).sum(),
+        };
+        let formats: usize = self.formats.iter().map(|f| f.data.len()).sum();
+        (content + formats) as u64
+    }
+
+    pub fn is_image(&self) -> bool {
+        matches!(self.content, ClipboardContent::Image { .. })
+    }
+}
+
+/// Lo que ocupa el historial, para aplicar los límites de los ajustes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    pub entries: usize,
+    pub bytes: u64,
+    pub image_bytes: u64,
+}
+
+impl Usage {
+    pub fn of<'a>(items: impl Iterator<Item = &'a HistoryItem>) -> Self {
+        let mut usage = Usage::default();
+        for item in items {
+            usage.add(item);
+        }
+        usage
+    }
+
+    pub fn add(&mut self, item: &HistoryItem) {
+        self.entries += 1;
+        self.bytes += item.size();
+        if item.is_image() {
+            self.image_bytes += item.size();
+        }
+    }
+
+    /// Sin bajar de cero, por si la cuenta se ha descuadrado (una
+    /// importación, una escritura que falló...).
+    pub fn sub(&mut self, item: &HistoryItem) {
+        self.entries = self.entries.saturating_sub(1);
+        self.bytes = self.bytes.saturating_sub(item.size());
+        if item.is_image() {
+            self.image_bytes = self.image_bytes.saturating_sub(item.size());
+        }
+    }
+}
+
+/// Una entrada tal y como se guarda en el índice (y en los archivos
+/// exportados). La imagen y los formatos van en ficheros aparte.
+#[derive(Serialize, Deserialize)]
+pub struct StoredEntry {
+    id: u64,
+    created: i64,
+    #[serde(default)]
+    source: Selection,
+    /// Tipos MIME de los ficheros `formats/<id>-<n>`, en orden.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    formats: Vec<String>,
+    #[serde(default)]
+    times_copied: u32,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pinned: bool,
+    #[serde(default)]
+    paste_keys: PasteKeys,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    sensitive: bool,
+    #[serde(default, skip_serializing_if = "is_default_expiry")]
+    expiry: Expiry,
+    #[serde(flatten)]
+    kind: StoredKind,
+}
+
+fn is_default_expiry(expiry: &Expiry) -> bool {
+    *expiry == Expiry::Default
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum StoredKind {
+    Text { text: String },
+    Image { width: usize, height: usize },
+    Files { uris: Vec<String>, cut: bool },
+}
+
+impl From<&HistoryItem> for StoredEntry {
+    fn from(item: &HistoryItem) -> Self {
+        StoredEntry {
+            id: item.id,
+            created: item.created,
+            source: item.source,
+            formats: item.formats.iter().map(|f| f.mime.clone()).collect(),
+            times_copied: item.times_copied,
+            pinned: item.pinned,
+            paste_keys: item.paste_keys,
+            sensitive: item.sensitive,
+            expiry: item.expiry,
+            kind: match &item.content {
+                ClipboardContent::Text { full, .. } => StoredKind::Text { text: full.clone() },
+                ClipboardContent::Image { raw, .. } => StoredKind::Image {
+                    width: raw.width,
+                    height: raw.height,
+                },
+                ClipboardContent::Files { uris, cut } => StoredKind::Files {
+                    uris: uris.clone(),
+                    cut: *cut,
+                },
+            },
+        }
+    }
+}
+
+impl StoredEntry {
+    /// Reconstruye la entrada. `read` devuelve el contenido de sus ficheros
+    /// a partir de su nombre relativo (`image_name`, `format_name`). Sin la
+    /// imagen no hay entrada; los formatos que falten se omiten. Si la
+    /// imagen se copió como `image/png` se saca de ese formato.
+    pub fn into_item(self, read: impl Fn(&str) -> Option<Vec<u8>>) -> Option<HistoryItem> {
+        let formats: Vec<MimeData> = self
+            .formats
+            .into_iter()
+            .enumerate()
+            .filter_map(|(n, mime)| {
+                let data = read(&format_name(self.id, n))?;
+                Some(MimeData { mime, data })
+            })
+            .collect();
+        let content = match self.kind {
+            StoredKind::Text { text } => ClipboardContent::Text {
+                display: compact_preview(&text),
+                full: text,
+            },
+            StoredKind::Image { .. } => {
+                let raw = formats
+                    .iter()
+                    .find(|f| f.mime == PNG_MIME)
+                    .and_then(|f| decode_png(&f.data))
+                    .or_else(|| decode_png(&read(&image_name(self.id))?))?;
+                ClipboardContent::Image {
+                    texture: raw_to_texture(raw.width as i32, raw.height as i32, &raw.data),
+                    raw,
+                }
+            }
+            StoredKind::Files { uris, cut } => ClipboardContent::Files { uris, cut },
+        };
+        Some(HistoryItem {
+            id: self.id,
+            created: self.created,
+            source: self.source,
+            hash: content_hash(&content),
+            content,
+            formats,
+            times_copied: self.times_copied.max(1),
+            pinned: self.pinned,
+            paste_keys: self.paste_keys,
+            sensitive: self.sensitive,
+            expiry: self.expiry,
+        })
+    }
+}
+
+/// Fichero de la imagen de una entrada, relativo al historial.
+pub fn image_name(id: u64) -> String {
+    format!("{}/{}.png", IMAGES_DIR, id)
+}
+
+/// Fichero del formato `n` de una entrada, relativo al historial.
+pub fn format_name(id: u64, n: usize) -> String {
+    format!("{}/{}-{}", FORMATS_DIR, id, n)
+}
+
+fn decode_png(data: &[u8]) -> Option<ImageDataOwned> {
+    let img = image::load_from_memory(data).ok()?.into_rgba8();
+    Some(ImageDataOwned {
+        width: img.width() as usize,
+        height: img.height() as usize,
+        data: img.into_raw(),
+    })
+}
+
+/// Trabajo de disco para el hilo del historial.
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Un fichero de una entrada pendiente de cifrar y escribir.
+enum Blob {
+    Image(ImageDataOwned),
+    Bytes(Vec<u8>),
+}
+
+/// Historial en disco bajo `~/.local/share/klipBored`.
+///
+/// El índice (`history.json`) se reescribe entero en cada cambio; las
+/// imágenes se guardan aparte como PNG en `images/<id>.png`, salvo que ya
+/// vengan como `image/png`, y los formatos tal cual en `formats/<id>-<n>`.
+/// Todos los ficheros van cifrados con la clave de `unlock`; hasta entonces
+/// no se lee ni se escribe nada.
+///
+/// Las escrituras y los borrados de ficheros van todos a un mismo hilo, en
+/// orden: así un borrado nunca adelanta a la escritura de la misma entrada.
+pub struct HistoryStore {
+    dir: PathBuf,
+    jobs: mpsc::Sender<Job>,
+    cipher: Option<Cipher>,
+    /// La clave de antes y la nueva mientras se vuelve a cifrar.
+    rekeying: Option<(Cipher, Cipher)>,
+    /// Para dejar a medias el cifrado en curso.
+    cancel_rekey: Arc<AtomicBool>,
+}
+
+impl HistoryStore {
+    pub fn open() -> Self {
+        let dir = data_dir();
+        let _ = fs::create_dir_all(dir.join(IMAGES_DIR));
+        let _ = fs::create_dir_all(dir.join(FORMATS_DIR));
+        let (jobs, rx) = mpsc::channel::<Job>();
+        std::thread::spawn(move || rx.into_iter().for_each(|job| job()));
+        Self {
+            dir,
+            jobs,
+            cipher: None,
+            rekeying: None,
+            cancel_rekey: Arc::default(),
+        }
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn is_rekeying(&self) -> bool {
+        self.rekeying.is_some()
+    }
+
+    /// Empieza a usar `cipher`. Falla si el historial se cifró con otra
+    /// clave; si aún no había ninguna, esta pasa a ser la del historial.
+    pub fn unlock(&mut self, cipher: Cipher) -> Result<(), Box<dyn Error>> {
+        let path = self.dir.join(CHECK_FILE);
+        match fs::read(&path) {
+            Ok(data) => {
+                let plain = vault::is_sealed(&data)
+                    .then(|| cipher.open(data).ok())
+                    .flatten();
+                if plain.as_deref() != Some(CHECK_PLAIN) {
+                    return Err("la clave no es la del historial".into());
+                }
+            }
+            Err(_) => write_atomic(&path, &cipher.seal(CHECK_PLAIN))?,
+        }
+        self.cipher = Some(cipher);
+        Ok(())
+    }
+
+    /// Vuelve a cifrar todo el historial con una clave nueva, en el hilo del
+    /// historial. Los ficheros cifrados con la clave nueva se escriben aparte
+    /// y se ponen en su sitio cuando están todos; solo entonces se guarda
+    /// `key`. Hasta que se llame a `finish_rekey` el historial queda cerrado.
+    pub fn rekey<'a>(
+        &mut self,
+        cipher: Cipher,
+        key: NewKey,
+        items: impl Iterator<Item = &'a HistoryItem>,
+        done: impl FnOnce(Result<(), String>) + Send + 'static,
+    ) {
+        let Some(old) = self.cipher.take() else {
+            return;
+        };
+        let items: Vec<&HistoryItem> = items.collect();
+        let blobs: Vec<_> = items.iter().flat_map(|item| self.blobs(item)).collect();
+        let stored: Vec<StoredEntry> = items.into_iter().map(StoredEntry::from).collect();
+        let index = match serde_json::to_vec(&stored) {
+            Ok(bytes) => cipher.seal(&bytes),
+            Err(e) => {
+                self.cipher = Some(old);
+                done(Err(e.to_string()));
+                return;
+            }
+        };
+        let mut sealed = vec![
+            (self.dir.join(INDEX_FILE), index),
+            (self.dir.join(CHECK_FILE), cipher.seal(CHECK_PLAIN)),
+        ];
+        self.rekeying = Some((old, cipher.clone()));
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.cancel_rekey = cancel.clone();
+        self.run(move || {
+            let cancelled = || cancel.load(Ordering::SeqCst);
+            sealed.extend(
+                blobs
+                    .into_iter()
+                    .take_while(|_| !cancelled())
+                    .filter_map(|(path, blob)| encode(blob).map(|data| (path, cipher.seal(&data)))),
+            );
+            let res = replace_all(&sealed, cancelled)
+                .map_err(|e| e.to_string())
+                .and_then(|()| {
+                    if cancelled() {
+                        return Err("cancelado".to_string());
+                    }
+                    key.save().map_err(|e| e.to_string())
+                });
+            done(res);
+        });
+    }
+
+    /// Deja a medias el cifrado en curso, si lo hay: no se toca nada de lo
+    /// que hay en disco ni se guarda la clave nueva.
+    pub fn cancel_rekey(&self) {
+        self.cancel_rekey.store(true, Ordering::SeqCst);
+    }
+
+    /// Vuelve a abrir el historial tras `rekey`: con la clave nueva si todo
+    /// ha ido bien y con la de antes si no.
+    pub fn finish_rekey(&mut self, ok: bool) {
+        if let Some((old, new)) = self.rekeying.take() {
+            self.cipher = Some(if ok { new } else { old });
+        }
+    }
+
+    /// Borra todo lo que hay en disco y olvida la clave. Antes espera a que
+    /// termine lo que quede por escribir, para que no vuelva a aparecer.
+    pub fn wipe(&mut self) {
+        self.cancel_rekey();
+        let (tx, rx) = mpsc::channel();
+        self.run(move || {
+            let _ = tx.send(());
+        });
+        let _ = rx.recv();
+        for dir in [IMAGES_DIR, FORMATS_DIR] {
+            let _ = fs::remove_dir_all(self.dir.join(dir));
+            let _ = fs::create_dir_all(self.dir.join(dir));
+        }
+        for file in [INDEX_FILE, CHECK_FILE] {
+            let _ = fs::remove_file(self.dir.join(file));
+        }
+        self.cipher = None;
+        self.rekeying = None;
+    }
+
+    /// Lee y descifra un fichero.
+    fn read(&self, path: &std::path::Path) -> Option<Vec<u8>> {
+        let data = fs::read(path).ok()?;
+        self.cipher.as_ref()?.open(data).ok()
+    }
+
+    fn image_path(&self, id: u64) -> PathBuf {
+        self.dir.join(image_name(id))
+    }
+
+    fn format_path(&self, id: u64, n: usize) -> PathBuf {
+        self.dir.join(format_name(id, n))
+    }
+
+    /// Carga el historial, de más reciente a más antiguo. Las entradas
+    /// cuya imagen no se pueda leer se descartan.
+    pub fn load(&self) -> Vec<HistoryItem> {
+        let Ok(raw) = fs::read(self.dir.join(INDEX_FILE)) else {
+            return Vec::new();
+        };
+        // Historial de antes del cifrado: se cifra entero al cargarlo
+        let plaintext = !vault::is_sealed(&raw);
+        let Some(raw) = self.cipher.as_ref().and_then(|c| c.open(raw).ok()) else {
+            return Vec::new();
+        };
+        let stored: Vec<StoredEntry> = match serde_json::from_slice(&raw) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("klipBored: historial corrupto, se ignora: {}", e);
+                return Vec::new();
+            }
+        };
+
+        let items: Vec<HistoryItem> = stored
+            .into_iter()
+            .filter_map(|entry| entry.into_item(|name| self.read(&self.dir.join(name))))
+            .collect();
+
+        if plaintext {
+            for item in &items {
+                self.add(item);
+            }
+            self.save(items.iter());
+        }
+        items
+    }
+
+    /// Guarda los datos propios de una entrada nueva: la imagen y los
+    /// formatos adicionales. Se escriben en otro hilo para no bloquear la UI.
+    pub fn add(&self, item: &HistoryItem) {
+        let Some(cipher) = self.cipher.clone() else {
+            return;
+        };
+        let blobs = self.blobs(item);
+        if blobs.is_empty() {
+            return;
+        }
+        self.run(move || write_blobs(&cipher, blobs));
+    }
+
+    /// Encola `job` en el hilo del historial.
+    fn run(&self, job: impl FnOnce() + Send + 'static) {
+        let _ = self.jobs.send(Box::new(job));
+    }
+
+    fn blobs(&self, item: &HistoryItem) -> Vec<(PathBuf, Blob)> {
+        let mut blobs = Vec::new();
+        if let ClipboardContent::Image { raw, .. } = &item.content {
+            if !item.formats.iter().any(|f| f.mime == PNG_MIME) {
+                blobs.push((self.image_path(item.id), Blob::Image(raw.clone())));
+            }
+        }
+        for (n, f) in item.formats.iter().enumerate() {
+            blobs.push((self.format_path(item.id, n), Blob::Bytes(f.data.clone())));
+        }
+        blobs
+    }
+
+    /// Borra los ficheros de una entrada, después de lo que quede por
+    /// escribir.
+    pub fn remove(&self, item: &HistoryItem) {
+        let mut paths = Vec::new();
+        if let ClipboardContent::Image { .. } = &item.content {
+            paths.push(self.image_path(item.id));
+        }
+        paths.extend((0..item.formats.len()).map(|n| self.format_path(item.id, n)));
+        self.run(move || {
+            for path in paths {
+                let _ = fs::remove_file(path);
+            }
+        });
+    }
+
+    /// Si es la primera vez que arrancamos en esta sesión gráfica. La
+    /// sesión se identifica por el arranque del sistema y `XDG_SESSION_ID`.
+    pub fn is_new_session(&self) -> bool {
+        let boot = fs::read_to_string("/proc/sys/kernel/random/boot_id").unwrap_or_default();
+        let session = std::env::var("XDG_SESSION_ID").unwrap_or_default();
+        let current = format!("{}:{}", boot.trim(), session);
+        let path = self.dir.join(SESSION_FILE);
+        if fs::read_to_string(&path).is_ok_and(|prev| prev == current) {
+            return false;
+        }
+        let _ = fs::write(path, current);
+        true
+    }
+
+    /// Reescribe el índice con las entradas actuales, en orden.
+    pub fn save<'a>(&self, items: impl Iterator<Item = &'a HistoryItem>) {
+        let Some(cipher) = &self.cipher else {
+            return;
+        };
+        let stored: Vec<StoredEntry> = items.map(StoredEntry::from).collect();
+
+        let res = serde_json::to_vec(&stored)
+            .map_err(io::Error::from)
+            .and_then(|bytes| write_atomic(&self.dir.join(INDEX_FILE), &cipher.seal(&bytes)));
+        if let Err(e) = res {
+            eprintln!("klipBored: no se pudo guardar el historial: {}", e);
+        }
+    }
+}
+
+/// Cifra y escribe los ficheros de las entradas.
+fn write_blobs(cipher: &Cipher, blobs: Vec<(PathBuf, Blob)>) {
+    for (path, blob) in blobs {
+        let Some(data) = encode(blob) else {
+            continue;
+        };
+        if let Err(e) = fs::write(&path, cipher.seal(&data)) {
+            eprintln!("klipBored: no se pudo guardar {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Lo que se escribe de cada fichero; las imágenes van como PNG.
+fn encode(blob: Blob) -> Option<Vec<u8>> {
+    match blob {
+        Blob::Image(raw) => crate::mime::encode_png(&raw),
+        Blob::Bytes(data) => Some(data),
+    }
+}
+
+/// Escribe todos los ficheros con otro nombre y, si no ha fallado ninguno
+/// ni hay que dejarlo (`cancelled`), los pone en su sitio. Si no, se borra lo
+/// escrito y no se toca nada.
+fn replace_all(files: &[(PathBuf, Vec<u8>)], cancelled: impl Fn() -> bool) -> io::Result<()> {
+    let new_path = |path: &Path| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".new");
+        PathBuf::from(name)
+    };
+    let written = files
+        .iter()
+        .try_for_each(|(path, bytes)| write_synced(&new_path(path), bytes))
+        .and_then(|()| {
+            if cancelled() {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "cancelado"));
+            }
+            Ok(())
+        });
+    if let Err(e) = written {
+        for (path, _) in files {
+            let _ = fs::remove_file(new_path(path));
+        }
+        return Err(e);
+    }
+    for (path, _) in files {
+        fs::rename(new_path(path), path)?;
+    }
+    Ok(())
+}
+
+fn write_synced(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut f = fs::File::create(path)?;
+    f.write_all(bytes)?;
+    f.sync_all()
+}
+
+/// Escribe a un fichero temporal y lo renombra, para que un cierre a mitad
+/// de escritura no deje el índice a medias.
+fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    write_synced(&tmp, bytes)?;
+    fs::rename(tmp, path)
+}
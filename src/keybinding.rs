@@ -0,0 +1,365 @@
+//! El atajo global de GNOME que abre el panel.
+//!
+//! Se registra como atajo personalizado de `gnome-settings-daemon` con
+//! `gio::Settings`. Antes se mira si otra acción de GNOME ya usa esa
+//! combinación; si el usuario decide quitársela, se guarda lo que tenía para
+//! devolvérselo cuando klipBored deje de usarla.
+//!
+//! Cada cambio toca varios esquemas a la vez (la lista de atajos
+//! personalizados, el de klipBored y los de otras acciones); se aplican
+//! todos juntos o, si alguno falla, no se aplica ninguno.
+
+use gtk::gio;
+use gtk::glib::{self, variant::ToVariant};
+use gtk::prelude::*;
+use serde::{Deserialize, Serialize};
+
+const MEDIA_KEYS: &str = "org.gnome.settings-daemon.plugins.media-keys";
+const CUSTOM: &str = "org.gnome.settings-daemon.plugins.media-keys.custom-keybinding";
+const CUSTOM_LIST: &str = "custom-keybindings";
+const CUSTOM_PATH: &str = "/org/gnome/settings-daemon/plugins/media-keys/custom-keybindings";
+const NAME: &str = "klipBored";
+const SHELL: &str = "org.gnome.shell.keybindings";
+const MESSAGE_LIST: &str = "message-list-toggle";
+
+/// Esquemas con atajos globales, y cómo se llaman en la interfaz.
+const SCHEMAS: &[(&str, &str)] = &[
+    ("org.gnome.desktop.wm.keybindings", "ventanas"),
+    (SHELL, "GNOME Shell"),
+    ("org.gnome.mutter.keybindings", "ventanas"),
+    ("org.gnome.mutter.wayland.keybindings", "ventanas"),
+    (MEDIA_KEYS, "teclas del sistema"),
+];
+
+/// Una acción de GNOME con atajo.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Action {
+    /// El esquema, o `esquema:ruta` si es un atajo personalizado.
+    pub schema: String,
+    pub key: String,
+    /// Para enseñarla al usuario.
+    pub name: String,
+    /// Lo que tenía antes, como texto de GVariant (`['<Super>v']`).
+    pub value: String,
+}
+
+impl Action {
+    fn new(schema: String, key: &str, name: String, value: &glib::Variant) -> Self {
+        Action {
+            schema,
+            key: key.to_string(),
+            name,
+            value: value.print(true).to_string(),
+        }
+    }
+
+    fn uses(&self, binding: &str) -> bool {
+        glib::Variant::parse(None, &self.value)
+            .is_ok_and(|value| accelerators(&value).iter().any(|a| same_accel(a, binding)))
+    }
+
+    fn settings(&self) -> Result<gio::Settings, glib::BoolError> {
+        match self.schema.split_once(':') {
+            Some((schema, path)) => settings(schema, Some(path)),
+            None => settings(&self.schema, None),
+        }
+    }
+
+    /// Le devuelve el valor que tenía.
+    fn restore(&self, tx: &mut Transaction) -> Result<(), glib::BoolError> {
+        let settings = self.settings()?;
+        let current = settings.value(&self.key);
+        let value = glib::Variant::parse(Some(current.type_()), &self.value)
+            .map_err(|e| glib::bool_error!("{}: {}", self.key, e))?;
+        tx.set(&settings, &self.key, &value)
+    }
+
+    /// Le quita `binding`, o se lo cambia por `replacement`.
+    fn replace(
+        &self,
+        tx: &mut Transaction,
+        binding: &str,
+        replacement: Option<&str>,
+    ) -> Result<(), glib::BoolError> {
+        let settings = self.settings()?;
+        let current = settings.value(&self.key);
+        let value = match current.get::<Vec<String>>() {
+            Some(accels) => accels
+                .into_iter()
+                .filter_map(|accel| {
+                    if same_accel(&accel, binding) {
+                        replacement.map(str::to_string)
+                    } else {
+                        Some(accel)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .to_variant(),
+            None => replacement.unwrap_or("").to_variant(),
+        };
+        tx.set(&settings, &self.key, &value)
+    }
+}
+
+/// Las acciones de GNOME que ya usan `binding`, salvo la de klipBored.
+pub fn conflicts(binding: &str) -> Vec<Action> {
+    let mut found = Vec::new();
+    for (schema, area) in SCHEMAS {
+        let Ok(settings) = settings(schema, None) else {
+            continue;
+        };
+        let keys = settings
+            .settings_schema()
+            .map(|s| s.list_keys())
+            .unwrap_or_default();
+        for key in keys {
+            let value = settings.value(&key);
+            if accelerators(&value).iter().any(|a| same_accel(a, binding)) {
+                let name = format!("{} ({})", key, area);
+                found.push(Action::new(schema.to_string(), &key, name, &value));
+            }
+        }
+    }
+    for path in custom_paths() {
+        let Ok(custom) = settings(CUSTOM, Some(&path)) else {
+            continue;
+        };
+        let name = custom.string("name");
+        if name.as_str() == NAME {
+            continue;
+        }
+        let value = custom.value("binding");
+        if accelerators(&value).iter().any(|a| same_accel(a, binding)) {
+            let schema = format!("{}:{}", CUSTOM, path);
+            let name = format!("{} (atajo personalizado)", name);
+            found.push(Action::new(schema, "binding", name, &value));
+        }
+    }
+    found
+}
+
+/// Registra `binding` para abrir klipBored. Antes quita el atajo a las
+/// acciones de `disable` y las apunta en `overridden`; las que ya estaban
+/// ahí y no chocan con `binding` recuperan su valor.
+pub fn register(
+    binding: &str,
+    disable: &[Action],
+    overridden: &mut Vec<Action>,
+) -> Result<(), glib::BoolError> {
+    let command = std::env::current_exe()
+        .ok()
+        .and_then(|p| p.to_str().map(str::to_string))
+        .ok_or_else(|| glib::bool_error!("no se encuentra el ejecutable"))?;
+    let (restore, keep): (Vec<Action>, Vec<Action>) =
+        overridden.iter().cloned().partition(|a| !a.uses(binding));
+    transaction(|tx| {
+        for action in &restore {
+            action.restore(tx)?;
+        }
+        for action in disable {
+            action.replace(tx, binding, None)?;
+        }
+        set_custom(tx, &command, binding)
+    })?;
+    *overridden = keep;
+    overridden.extend(disable.iter().cloned());
+    Ok(())
+}
+
+/// Quita el atajo de klipBored de GNOME y devuelve a las acciones de
+/// `overridden` el que tenían. Para cuando el atajo lo lleva el portal.
+/// Devuelve si había algo que quitar.
+pub fn unregister(overridden: &mut Vec<Action>) -> Result<bool, glib::BoolError> {
+    let paths = custom_paths();
+    let ours = find_ours(&paths);
+    if ours.is_none() && overridden.is_empty() {
+        return Ok(false);
+    }
+    transaction(|tx| {
+        for action in overridden.iter() {
+            action.restore(tx)?;
+        }
+        if let Some(ours) = &ours {
+            let rest: Vec<String> = paths.iter().filter(|path| *path != ours).cloned().collect();
+            tx.set(
+                &settings(MEDIA_KEYS, None)?,
+                CUSTOM_LIST,
+                &rest.to_variant(),
+            )?;
+            let custom = settings(CUSTOM, Some(ours))?;
+            for key in ["name", "command", "binding"] {
+                tx.reset(&custom, key);
+            }
+        }
+        Ok(())
+    })?;
+    overridden.clear();
+    Ok(true)
+}
+
+/// Si está registrado el atajo personalizado de klipBored.
+pub fn is_registered() -> bool {
+    find_ours(&custom_paths()).is_some()
+}
+
+/// Las versiones sin `config.toml` vaciaban `message-list-toggle` (Super+V
+/// en Ubuntu) al elegir Super+V sin apuntar lo que tenía; se da por hecho
+/// que era el de Ubuntu, que es lo que esas versiones volvían a poner.
+pub fn legacy_override(binding: &str) -> Option<Action> {
+    if !same_accel(binding, "<Super>v") {
+        return None;
+    }
+    let shell = settings(SHELL, None).ok()?;
+    let current = shell.value(MESSAGE_LIST);
+    if !accelerators(&current).is_empty() {
+        return None;
+    }
+    Some(Action::new(
+        SHELL.to_string(),
+        MESSAGE_LIST,
+        format!("{} (GNOME Shell)", MESSAGE_LIST),
+        &vec!["<Super>v".to_string()].to_variant(),
+    ))
+}
+
+/// Cambia `binding` por `replacement` en el atajo de `action` y la apunta
+/// en `overridden` con el que tenía, para devolvérselo con `reset`.
+pub fn reassign(
+    action: &Action,
+    binding: &str,
+    replacement: &str,
+    overridden: &mut Vec<Action>,
+) -> Result<(), glib::BoolError> {
+    transaction(|tx| action.replace(tx, binding, Some(replacement)))?;
+    // Si ya estaba, lo que se apuntó entonces es lo original
+    if !overridden
+        .iter()
+        .any(|a| a.schema == action.schema && a.key == action.key)
+    {
+        overridden.push(action.clone());
+    }
+    Ok(())
+}
+
+fn set_custom(tx: &mut Transaction, command: &str, binding: &str) -> Result<(), glib::BoolError> {
+    let mut paths = custom_paths();
+    let path = match find_ours(&paths) {
+        Some(path) => path,
+        None => {
+            let path = (0..)
+                .map(|idx| format!("{}/custom{}/", CUSTOM_PATH, idx))
+                .find(|path| !paths.contains(path))
+                .expect("siempre hay un hueco");
+            paths.push(path.clone());
+            tx.set(
+                &settings(MEDIA_KEYS, None)?,
+                CUSTOM_LIST,
+                &paths.to_variant(),
+            )?;
+            path
+        }
+    };
+    let custom = settings(CUSTOM, Some(&path))?;
+    tx.set(&custom, "name", &NAME.to_variant())?;
+    tx.set(&custom, "command", &command.to_variant())?;
+    tx.set(&custom, "binding", &binding.to_variant())
+}
+
+/// La ruta del atajo personalizado de klipBored entre `paths`.
+fn find_ours(paths: &[String]) -> Option<String> {
+    paths
+        .iter()
+        .find(|path| {
+            settings(CUSTOM, Some(path)).is_ok_and(|custom| custom.string("name").as_str() == NAME)
+        })
+        .cloned()
+}
+
+fn custom_paths() -> Vec<String> {
+    settings(MEDIA_KEYS, None)
+        .ok()
+        .and_then(|media| media.value(CUSTOM_LIST).get())
+        .unwrap_or_default()
+}
+
+/// Los atajos de un valor, sea una lista (`as`) o uno solo (`s`).
+fn accelerators(value: &glib::Variant) -> Vec<String> {
+    if let Some(accels) = value.get::<Vec<String>>() {
+        return accels;
+    }
+    value
+        .str()
+        .filter(|accel| !accel.is_empty())
+        .map(|accel| vec![accel.to_string()])
+        .unwrap_or_default()
+}
+
+/// `<Primary>` y `<Control>`, o `V` y `v`, son el mismo atajo.
+fn same_accel(a: &str, b: &str) -> bool {
+    match (gtk::accelerator_parse(a), gtk::accelerator_parse(b)) {
+        (Some((ka, ma)), Some((kb, mb))) => ka.to_lower() == kb.to_lower() && ma == mb,
+        _ => false,
+    }
+}
+
+/// `gio::Settings::new` aborta si el esquema no está instalado (fuera de
+/// GNOME); aquí es un error más.
+fn settings(schema: &str, path: Option<&str>) -> Result<gio::Settings, glib::BoolError> {
+    let found = gio::SettingsSchemaSource::default().and_then(|source| source.lookup(schema, true));
+    let Some(found) = found else {
+        return Err(glib::bool_error!("no está instalado el esquema {}", schema));
+    };
+    Ok(gio::Settings::new_full(
+        &found,
+        None::<&gio::SettingsBackend>,
+        path,
+    ))
+}
+
+/// Cambios retenidos (`delay`) en varios esquemas hasta que se aplican.
+#[derive(Default)]
+struct Transaction(Vec<gio::Settings>);
+
+impl Transaction {
+    fn hold(&mut self, settings: &gio::Settings) {
+        if !self.0.contains(settings) {
+            settings.delay();
+            self.0.push(settings.clone());
+        }
+    }
+
+    fn set(
+        &mut self,
+        settings: &gio::Settings,
+        key: &str,
+        value: &glib::Variant,
+    ) -> Result<(), glib::BoolError> {
+        self.hold(settings);
+        if !settings.is_writable(key) {
+            return Err(glib::bool_error!("{} no se puede cambiar", key));
+        }
+        settings.set_value(key, value)
+    }
+
+    fn reset(&mut self, settings: &gio::Settings, key: &str) {
+        self.hold(settings);
+        settings.reset(key);
+    }
+}
+
+/// Hace los cambios de `f`. Si alguno falla, deshace los anteriores.
+fn transaction(
+    f: impl FnOnce(&mut Transaction) -> Result<(), glib::BoolError>,
+) -> Result<(), glib::BoolError> {
+    let mut tx = Transaction::default();
+    let res = f(&mut tx);
+    for settings in &tx.0 {
+        if res.is_ok() {
+            settings.apply();
+        } else {
+            settings.revert();
+        }
+    }
+    gio::Settings::sync();
+    res
+}
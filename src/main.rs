@@ -1,18 +1,57 @@
+mod archive;
+mod cli;
+mod dbus;
+mod global_shortcut;
+mod history;
+mod keybinding;
+mod mime;
+mod paste;
+mod portal;
+mod reset;
+mod search;
+mod sensitive;
+mod settings;
+mod shortcut;
+mod vault;
+mod watcher;
+
 use adw::prelude::*;
-use arboard::{Clipboard, ImageData};
+use arboard::{Clipboard, GetExtLinux, ImageData, LinuxClipboardKind, SetExtLinux};
 use gtk::{gdk, gio, glib, pango};
+use history::{Expiry, HistoryItem, HistoryStore, Usage};
+use mime::MimeData;
+use paste::PasteKeys;
 use relm4::prelude::*;
+use settings::{ClearSchedule, CopyTarget, SensitiveMode, Settings, Theme};
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::hash_map::DefaultHasher;
 use std::fs;
 use std::hash::{Hash, Hasher};
-use std::process::Command;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use watcher::Selection;
 
 const APP_CSS: &str = include_str!("style.css");
 const APP_ICON_SVG: &[u8] = include_bytes!("../assets/klipbored.svg");
+const SHORTCUTS_UI: &str = include_str!("shortcuts.ui");
+// Una selección con el ratón cambia PRIMARY muchas veces seguidas; solo la
+// leemos cuando lleva este tiempo quieta.
+const PRIMARY_DEBOUNCE: Duration = Duration::from_millis(500);
+// Selecciones parciales dentro de este margen sustituyen a la anterior.
+const PRIMARY_MERGE_WINDOW: i64 = 5;
+// Lo que tarda el foco en volver a la ventana anterior tras ocultarnos.
+const PASTE_DELAY: Duration = Duration::from_millis(300);
+// Cada cuánto se buscan entradas caducadas.
+const PRUNE_INTERVAL: u32 = 60;
+const DAY: i64 = 24 * 60 * 60;
+// Caracteres de vista previa por entrada en `klipBored list`.
+const CLI_PREVIEW_CHARS: usize = 80;
+// Trozos en que se lee la entrada estándar de `klipBored copy`.
+const INPUT_CHUNK: usize = 64 * 1024;
 
 fn load_css() {
     let provider = gtk::CssProvider::new();
@@ -37,23 +76,28 @@ fn calculate_hash<T: Hash>(t: &T) -> u64 {
     s.finish()
 }
 
-fn config_file() -> std::path::PathBuf {
-    glib::user_config_dir().join("klipBored").join("keybinding")
+/// Identifica un contenido para detectar duplicados en el historial. En las
+/// imágenes coincide con el hash que guarda `ClipboardTracker`.
+fn content_hash(content: &ClipboardContent) -> u64 {
+    match content {
+        ClipboardContent::Text { full, .. } => calculate_hash(&("text", full)),
+        ClipboardContent::Image { raw, .. } => calculate_hash(&raw.data),
+        ClipboardContent::Files { uris, cut } => calculate_hash(&("files", uris, cut)),
+    }
 }
 
-fn has_keybinding() -> bool {
-    config_file().exists()
-        && fs::read_to_string(config_file()).map_or(false, |s| !s.trim().is_empty())
-}
+const DEFAULT_BINDING: &str = "<Super>v";
 
-fn save_keybinding(binding: &str) {
-    let path = config_file();
-    let _ = fs::create_dir_all(path.parent().unwrap());
-    let _ = fs::write(path, binding);
+fn has_keybinding() -> bool {
+    !Settings::load().0.keybinding.trim().is_empty()
 }
 
-fn get_keybinding() -> String {
-    fs::read_to_string(config_file()).unwrap_or_else(|_| "<Super>v".to_string())
+fn apply_theme(theme: Theme) {
+    adw::StyleManager::default().set_color_scheme(match theme {
+        Theme::System => adw::ColorScheme::Default,
+        Theme::Light => adw::ColorScheme::ForceLight,
+        Theme::Dark => adw::ColorScheme::ForceDark,
+    });
 }
 
 fn autostart_file() -> std::path::PathBuf {
@@ -109,27 +153,230 @@ enum ClipboardContent {
         texture: gdk::Texture,
         raw: ImageDataOwned,
     },
+    /// Ficheros copiados (o cortados) desde un gestor de archivos.
+    Files {
+        uris: Vec<String>,
+        cut: bool,
+    },
 }
 
 impl ClipboardEntry {
     fn view_mode(&self) -> &str {
-        match &self.content {
+        match &self.item.content {
             ClipboardContent::Text { .. } => "text_page",
             ClipboardContent::Image { .. } => "image_page",
+            ClipboardContent::Files { .. } => "files_page",
         }
     }
-    fn display_text(&self) -> String {
-        match &self.content {
-            ClipboardContent::Text { display, .. } => display.clone(),
+    /// Vista previa del texto, con las coincidencias de la búsqueda resaltadas.
+    /// La de las entradas sensibles no deja ver nada.
+    fn display_markup(&self) -> String {
+        if self.item.sensitive {
+            return "••••••••••••".to_string();
+        }
+        if let Some(markup) = &self.highlight {
+            return markup.clone();
+        }
+        match &self.item.content {
+            ClipboardContent::Text { display, .. } => glib::markup_escape_text(display).into(),
             _ => String::new(),
         }
     }
+    /// Aplica la búsqueda a la entrada. Se busca en el texto completo; si lo
+    /// encontrado no sale en la vista previa se enseña el trozo donde está.
+    fn set_query(&mut self, query: &str) {
+        self.highlight = None;
+        self.matches = true;
+        if query.trim().is_empty() {
+            return;
+        }
+        // Buscar en ellas serviría para adivinar lo que ocultan
+        if self.item.sensitive {
+            self.matches = false;
+            return;
+        }
+        match &self.item.content {
+            ClipboardContent::Text { full, display } => {
+                let Some(found) = search::find(full, query) else {
+                    self.matches = false;
+                    return;
+                };
+                let (shown, hits) = match search::find(display, query) {
+                    Some(hits) => (display.as_str(), hits),
+                    None => {
+                        let shown = search::snippet(full, found[0], 300);
+                        (shown, search::find(shown, query).unwrap_or_default())
+                    }
+                };
+                self.highlight = Some(search::highlight(shown, &hits));
+            }
+            ClipboardContent::Files { uris, .. } => {
+                self.matches = uris.iter().any(|uri| {
+                    let path = gio::File::for_uri(uri)
+                        .path()
+                        .map_or_else(|| uri.clone(), |p| p.to_string_lossy().into_owned());
+                    search::find(&path, query).is_some()
+                });
+            }
+            ClipboardContent::Image { .. } => self.matches = false,
+        }
+    }
     fn texture(&self) -> Option<gdk::Paintable> {
-        match &self.content {
+        match &self.item.content {
             ClipboardContent::Image { texture, .. } => Some(texture.clone().upcast()),
             _ => None,
         }
     }
+    /// Formatos ricos de la entrada, p. ej. "HTML · RTF".
+    fn format_tags(&self) -> String {
+        let mut tags: Vec<&str> = Vec::new();
+        for label in self
+            .item
+            .formats
+            .iter()
+            .filter_map(|f| mime::short_label(&f.mime))
+        {
+            if !tags.contains(&label) {
+                tags.push(label);
+            }
+        }
+        tags.join(" · ")
+    }
+    fn has_tags(&self) -> bool {
+        self.item.source == Selection::Primary
+            || self.is_cut()
+            || self.item.times_copied > 1
+            || !self.format_tags().is_empty()
+            || self.pastes_in_terminal()
+            || self.item.sensitive
+    }
+    fn is_cut(&self) -> bool {
+        matches!(self.item.content, ClipboardContent::Files { cut: true, .. })
+    }
+    fn pastes_in_terminal(&self) -> bool {
+        self.item.paste_keys == PasteKeys::CtrlShiftV
+    }
+    fn expiry_tooltip(&self) -> String {
+        match self.item.expiry {
+            Expiry::Default => "Caducidad según los ajustes".to_string(),
+            Expiry::Never => "No caduca".to_string(),
+            Expiry::At(t) => glib::DateTime::from_unix_local(t)
+                .and_then(|d| d.format("Caduca el %d/%m a las %H:%M"))
+                .map_or_else(|_| "Caduca pronto".to_string(), String::from),
+        }
+    }
+}
+
+// Caducidades que se pueden elegir para una entrada; `None` es "nunca" y
+// `Some(0)`, volver a la de los ajustes
+const EXPIRY_CHOICES: [(&str, Option<i64>); 5] = [
+    ("Según los ajustes", Some(0)),
+    ("En 1 hora", Some(60 * 60)),
+    ("En 1 día", Some(DAY)),
+    ("En 1 semana", Some(7 * DAY)),
+    ("Nunca", None),
+];
+
+/// Menú para copiar una entrada en el portapapeles, en la selección
+/// primaria o en ambos, sin cambiar el ajuste.
+fn copy_target_popover(
+    sender: &FactorySender<ClipboardEntry>,
+    index: &DynamicIndex,
+) -> gtk::Popover {
+    let popover = gtk::Popover::new();
+    let list = gtk::Box::new(gtk::Orientation::Vertical, 2);
+    let title = gtk::Label::new(Some("Copiar en"));
+    title.add_css_class("dim-label");
+    list.append(&title);
+    for target in CopyTarget::ALL {
+        let button = gtk::Button::with_label(target.label());
+        button.add_css_class("flat");
+        let (sender, index, popover_cb) = (sender.clone(), index.clone(), popover.clone());
+        button.connect_clicked(move |_| {
+            popover_cb.popdown();
+            sender
+                .output(ClipboardEntryOutput::CopyTo(index.clone(), target))
+                .unwrap();
+        });
+        list.append(&button);
+    }
+    popover.set_child(Some(&list));
+    popover
+}
+
+/// Menú para cambiar la caducidad de una entrada.
+fn expiry_popover(sender: &FactorySender<ClipboardEntry>, index: &DynamicIndex) -> gtk::Popover {
+    let popover = gtk::Popover::new();
+    let list = gtk::Box::new(gtk::Orientation::Vertical, 2);
+    for (label, secs) in EXPIRY_CHOICES {
+        let button = gtk::Button::with_label(label);
+        button.add_css_class("flat");
+        let (sender, index, popover_cb) = (sender.clone(), index.clone(), popover.clone());
+        button.connect_clicked(move |_| {
+            let expiry = match secs {
+                Some(0) => Expiry::Default,
+                Some(secs) => Expiry::At(history::now_unix() + secs),
+                None => Expiry::Never,
+            };
+            popover_cb.popdown();
+            sender
+                .output(ClipboardEntryOutput::SetExpiry(index.clone(), expiry))
+                .unwrap();
+        });
+        list.append(&button);
+    }
+    popover.set_child(Some(&list));
+    popover
+}
+
+// Ficheros que se listan en la fila; el resto se resume en "y N más"
+const MAX_LISTED_FILES: usize = 4;
+
+/// Lista de ficheros con el icono de su tipo, para las entradas `Files`.
+fn files_box(item: &HistoryItem) -> gtk::Box {
+    let list = gtk::Box::new(gtk::Orientation::Vertical, 4);
+    let ClipboardContent::Files { uris, .. } = &item.content else {
+        return list;
+    };
+    for uri in uris.iter().take(MAX_LISTED_FILES) {
+        let file = gio::File::for_uri(uri);
+        let name = file
+            .basename()
+            .map_or_else(|| uri.clone(), |n| n.to_string_lossy().into_owned());
+        let (content_type, _) = gio::content_type_guess(Some(name.as_str()), None);
+        let image = gtk::Image::from_gicon(&gio::content_type_get_icon(&content_type));
+        // El icono de verdad (las carpetas, por ejemplo) lo da el sistema de
+        // ficheros, que puede tardar: llega después sin bloquear la lista
+        let weak = image.downgrade();
+        file.query_info_async(
+            "standard::icon",
+            gio::FileQueryInfoFlags::NONE,
+            glib::Priority::LOW,
+            gio::Cancellable::NONE,
+            move |res| {
+                let icon = res.ok().and_then(|info| info.icon());
+                if let (Some(icon), Some(image)) = (icon, weak.upgrade()) {
+                    image.set_from_gicon(&icon);
+                }
+            },
+        );
+
+        let row = gtk::Box::new(gtk::Orientation::Horizontal, 8);
+        row.append(&image);
+        let label = gtk::Label::new(Some(&name));
+        label.set_ellipsize(pango::EllipsizeMode::Middle);
+        label.set_xalign(0.0);
+        label.set_tooltip_text(file.path().as_deref().and_then(|p| p.to_str()));
+        row.append(&label);
+        list.append(&row);
+    }
+    if uris.len() > MAX_LISTED_FILES {
+        let more = gtk::Label::new(Some(&format!("y {} más", uris.len() - MAX_LISTED_FILES)));
+        more.set_xalign(0.0);
+        more.add_css_class("files-more");
+        list.append(&more);
+    }
+    list
 }
 
 fn compact_preview(text: &str) -> String {
@@ -146,6 +393,23 @@ fn compact_preview(text: &str) -> String {
     result
 }
 
+fn texture_to_raw(texture: &gdk::Texture) -> ImageDataOwned {
+    let mut downloader = gdk::TextureDownloader::new(texture);
+    downloader.set_format(gdk::MemoryFormat::R8g8b8a8);
+    let (bytes, stride) = downloader.download_bytes();
+    let width = texture.width() as usize;
+    let height = texture.height() as usize;
+    let mut data = Vec::with_capacity(width * height * 4);
+    for row in bytes.chunks(stride).take(height) {
+        data.extend_from_slice(&row[..width * 4]);
+    }
+    ImageDataOwned {
+        width,
+        height,
+        data,
+    }
+}
+
 fn raw_to_texture(width: i32, height: i32, data: &[u8]) -> gdk::Texture {
     let bytes = glib::Bytes::from(data);
     gdk::MemoryTexture::new(
@@ -160,18 +424,27 @@ fn raw_to_texture(width: i32, height: i32, data: &[u8]) -> gdk::Texture {
 
 #[derive(Debug)]
 struct ClipboardEntry {
-    content: ClipboardContent,
+    item: HistoryItem,
+    /// Marcado con las coincidencias de la búsqueda actual, si hay.
+    highlight: Option<String>,
+    /// Si pasa el filtro de la búsqueda actual.
+    matches: bool,
 }
 
 #[derive(Debug)]
 enum ClipboardEntryOutput {
     RequestCopy(DynamicIndex),
     DeleteItem(DynamicIndex),
+    TogglePin(DynamicIndex),
+    TogglePasteKeys(DynamicIndex),
+    SetExpiry(DynamicIndex, Expiry),
+    /// Copiar en un sitio distinto del de los ajustes.
+    CopyTo(DynamicIndex, CopyTarget),
 }
 
 #[relm4::factory]
 impl FactoryComponent for ClipboardEntry {
-    type Init = ClipboardContent;
+    type Init = HistoryItem;
     type Input = ();
     type Output = ClipboardEntryOutput;
     type CommandOutput = ();
@@ -183,33 +456,97 @@ impl FactoryComponent for ClipboardEntry {
             set_spacing: 12,
             add_css_class: "clipboard-row",
             set_valign: gtk::Align::Start,
+            #[watch]
+            set_class_active: ("pinned", self.item.pinned),
+            #[watch]
+            set_class_active: ("filtered-out", !self.matches),
 
-            gtk::Stack {
+            gtk::Box {
+                set_orientation: gtk::Orientation::Vertical,
+                set_spacing: 6,
                 set_hexpand: true,
                 set_valign: gtk::Align::Center,
 
-                add_named[Some("text_page")] = &gtk::Label {
-                    set_halign: gtk::Align::Start,
-                    set_wrap: true,
-                    set_wrap_mode: pango::WrapMode::WordChar,
-                    set_ellipsize: pango::EllipsizeMode::End,
-                    set_lines: 4,
-                    set_xalign: 0.0,
+                gtk::Stack {
+                    add_named[Some("text_page")] = &gtk::Label {
+                        set_halign: gtk::Align::Start,
+                        set_wrap: true,
+                        set_wrap_mode: pango::WrapMode::WordChar,
+                        set_ellipsize: pango::EllipsizeMode::End,
+                        set_lines: 4,
+                        set_xalign: 0.0,
+                        #[watch]
+                        set_markup: &self.display_markup(),
+                    },
+
+                    add_named[Some("image_page")] = &gtk::Picture {
+                        set_content_fit: gtk::ContentFit::Contain,
+                        set_can_shrink: true,
+                        set_height_request: 100,
+                        add_css_class: "clipboard-img",
+                        #[watch]
+                        set_paintable: self.texture().as_ref(),
+                    },
+
+                    add_named: (&files_box(&self.item), Some("files_page")),
+
                     #[watch]
-                    set_label: &self.display_text(),
+                    set_visible_child_name: self.view_mode(),
                 },
 
-                add_named[Some("image_page")] = &gtk::Picture {
-                    set_keep_aspect_ratio: true,
-                    set_can_shrink: true,
-                    set_height_request: 100,
-                    add_css_class: "clipboard-img",
+                gtk::Box {
+                    set_orientation: gtk::Orientation::Horizontal,
+                    set_spacing: 6,
                     #[watch]
-                    set_paintable: self.texture().as_ref(),
-                },
+                    set_visible: self.has_tags(),
 
-                #[watch]
-                set_visible_child_name: self.view_mode(),
+                    gtk::Label {
+                        set_label: "Selección primaria",
+                        add_css_class: "entry-tag",
+                        #[watch]
+                        set_visible: self.item.source == Selection::Primary,
+                    },
+
+                    gtk::Label {
+                        add_css_class: "entry-tag",
+                        #[watch]
+                        set_label: &format!("×{}", self.item.times_copied),
+                        #[watch]
+                        set_tooltip_text: Some(format!("Copiado {} veces", self.item.times_copied).as_str()),
+                        #[watch]
+                        set_visible: self.item.times_copied > 1,
+                    },
+
+                    gtk::Label {
+                        set_label: "Cortado",
+                        add_css_class: "entry-tag",
+                        #[watch]
+                        set_visible: self.is_cut(),
+                    },
+
+                    gtk::Label {
+                        add_css_class: "entry-tag",
+                        #[watch]
+                        set_label: &self.format_tags(),
+                        #[watch]
+                        set_visible: !self.format_tags().is_empty(),
+                    },
+
+                    gtk::Label {
+                        set_label: "Sensible",
+                        add_css_class: "entry-tag",
+                        add_css_class: "sensitive",
+                        #[watch]
+                        set_visible: self.item.sensitive,
+                    },
+
+                    gtk::Label {
+                        set_label: "Ctrl + Shift + V",
+                        add_css_class: "entry-tag",
+                        #[watch]
+                        set_visible: self.pastes_in_terminal(),
+                    },
+                },
             },
 
             gtk::Box {
@@ -222,6 +559,47 @@ impl FactoryComponent for ClipboardEntry {
                         sender.output(ClipboardEntryOutput::RequestCopy(index.clone())).unwrap();
                     }
                 },
+                gtk::MenuButton {
+                    set_icon_name: "view-more-symbolic",
+                    add_css_class: "copy-target-btn",
+                    set_tooltip_text: Some("Copiar en…"),
+                    set_popover: Some(&copy_target_popover(&sender, index)),
+                },
+                gtk::Button {
+                    set_icon_name: "view-pin-symbolic",
+                    add_css_class: "pin-btn",
+                    #[watch]
+                    set_class_active: ("active", self.item.pinned),
+                    #[watch]
+                    set_tooltip_text: Some(if self.item.pinned { "Soltar" } else { "Fijar" }),
+                    connect_clicked[sender, index] => move |_| {
+                        sender.output(ClipboardEntryOutput::TogglePin(index.clone())).unwrap();
+                    }
+                },
+                gtk::MenuButton {
+                    set_icon_name: "alarm-symbolic",
+                    add_css_class: "expiry-btn",
+                    set_popover: Some(&expiry_popover(&sender, index)),
+                    #[watch]
+                    set_class_active: ("active", self.item.expiry != Expiry::Default),
+                    #[watch]
+                    set_tooltip_text: Some(&self.expiry_tooltip()),
+                },
+                gtk::Button {
+                    set_icon_name: "utilities-terminal-symbolic",
+                    add_css_class: "paste-keys-btn",
+                    #[watch]
+                    set_class_active: ("active", self.pastes_in_terminal()),
+                    #[watch]
+                    set_tooltip_text: Some(if self.pastes_in_terminal() {
+                        "Pegar con Ctrl + Shift + V (terminales)"
+                    } else {
+                        "Pegar con Ctrl + V"
+                    }),
+                    connect_clicked[sender, index] => move |_| {
+                        sender.output(ClipboardEntryOutput::TogglePasteKeys(index.clone())).unwrap();
+                    }
+                },
                 gtk::Button {
                     set_icon_name: "user-trash-symbolic",
                     add_css_class: "delete-btn",
@@ -232,45 +610,434 @@ impl FactoryComponent for ClipboardEntry {
             }
         }
     }
-    fn init_model(content: Self::Init, _: &DynamicIndex, _: FactorySender<Self>) -> Self {
-        Self { content }
+    fn init_model(item: Self::Init, _: &DynamicIndex, _: FactorySender<Self>) -> Self {
+        Self {
+            item,
+            highlight: None,
+            matches: true,
+        }
     }
 }
 
+/// Lo último visto en cada selección, para no guardar dos veces lo mismo.
+/// Se comparte con los hilos que leen el portapapeles; el cerrojo solo se
+/// toma para comparar, nunca mientras se lee.
+#[derive(Default)]
 struct ClipboardTracker {
     last_text: String,
+    last_primary_text: String,
     last_img_hash: u64,
-    last_own_copy: Instant,
+    last_files: Vec<String>,
+}
+
+impl ClipboardTracker {
+    /// Lee la selección indicada y devuelve su contenido si es nuevo.
+    /// Bloquea hasta que responde el dueño de la selección, que puede ser
+    /// esta misma aplicación: se llama desde otro hilo.
+    fn capture(tracker: &Mutex<Self>, selection: Selection) -> Option<Captured> {
+        let see = |f: &dyn Fn(&mut Self) -> bool| f(&mut lock(tracker));
+        let mut cb = Clipboard::new().ok()?;
+        if selection == Selection::Primary {
+            // De PRIMARY solo nos interesa texto
+            let text = cb
+                .get()
+                .clipboard(LinuxClipboardKind::Primary)
+                .text()
+                .ok()?;
+            if text.trim().is_empty() || !see(&|t| t.see_primary(&text)) {
+                return None;
+            }
+            if sensitive::has_secret_hint(&mime::read_hints(selection)) {
+                return None;
+            }
+            return Some(Captured::text(text, mime::read(selection), selection));
+        }
+        // Los ficheros copiados también se ofrecen como texto (sus rutas), así
+        // que se miran antes que el texto
+        let files = mime::read_files(selection);
+        if let Some((uris, cut)) = mime::parse_files(&files) {
+            if !see(&|t| t.see_files(&uris)) {
+                return None;
+            }
+            return Some(Captured {
+                content: ClipboardContent::Files { uris, cut },
+                formats: files,
+                source: selection,
+            });
+        }
+        if let Ok(text) = cb.get_text() {
+            if !text.is_empty() && see(&|t| t.see_text(&text)) {
+                // Copiado desde un gestor de contraseñas: no se guarda nunca
+                if sensitive::has_secret_hint(&mime::read_hints(selection)) {
+                    return None;
+                }
+                return Some(Captured::text(text, mime::read(selection), selection));
+            }
+        }
+        if let Ok(img) = cb.get_image() {
+            if img.bytes.is_empty() || !see(&|t| t.see_image(calculate_hash(&img.bytes))) {
+                return None;
+            }
+            let owned = ImageDataOwned {
+                width: img.width,
+                height: img.height,
+                data: img.bytes.into_owned(),
+            };
+            let tex = raw_to_texture(owned.width as i32, owned.height as i32, &owned.data);
+            return Some(Captured {
+                content: ClipboardContent::Image {
+                    texture: tex,
+                    raw: owned,
+                },
+                formats: mime::read(selection),
+                source: selection,
+            });
+        }
+
+        // arboard solo entiende image/png; JPEG o SVG sueltos los decodifica GDK
+        let formats = mime::read(selection);
+        let (texture, raw) = decode_image(&formats)?;
+        if !see(&|t| t.see_image(calculate_hash(&raw.data))) {
+            return None;
+        }
+        Some(Captured {
+            content: ClipboardContent::Image { texture, raw },
+            formats,
+            source: selection,
+        })
+    }
+
+    /// Apunta `content` como lo último del portapapeles. Lo de otro tipo se
+    /// olvida: si vuelve a copiarse después, es una copia nueva.
+    fn remember(&mut self, content: &ClipboardContent) {
+        self.forget();
+        match content {
+            ClipboardContent::Text { full, .. } => self.last_text = full.clone(),
+            ClipboardContent::Image { raw, .. } => self.last_img_hash = calculate_hash(&raw.data),
+            ClipboardContent::Files { uris, .. } => self.last_files = uris.clone(),
+        }
+    }
+
+    /// Si `text` es nuevo en el portapapeles; si lo es, queda apuntado.
+    fn see_text(&mut self, text: &str) -> bool {
+        if text == self.last_text {
+            return false;
+        }
+        self.forget();
+        self.last_text = text.to_string();
+        true
+    }
+
+    fn see_primary(&mut self, text: &str) -> bool {
+        if text == self.last_primary_text || text == self.last_text {
+            return false;
+        }
+        self.last_primary_text = text.to_string();
+        true
+    }
+
+    fn see_files(&mut self, uris: &[String]) -> bool {
+        if uris == self.last_files {
+            return false;
+        }
+        self.forget();
+        self.last_files = uris.to_vec();
+        true
+    }
+
+    fn see_image(&mut self, hash: u64) -> bool {
+        if hash == self.last_img_hash {
+            return false;
+        }
+        self.forget();
+        self.last_img_hash = hash;
+        true
+    }
+
+    fn forget(&mut self) {
+        self.last_text.clear();
+        self.last_img_hash = 0;
+        self.last_files.clear();
+    }
+}
+
+/// Un hilo que lee el portapapeles puede haber entrado en pánico con el
+/// cerrojo tomado; lo apuntado sigue valiendo.
+fn lock(tracker: &Mutex<ClipboardTracker>) -> MutexGuard<'_, ClipboardTracker> {
+    tracker.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Lo leído del portapapeles, antes de convertirse en entrada del historial.
+#[derive(Debug)]
+struct Captured {
+    content: ClipboardContent,
+    formats: Vec<MimeData>,
+    source: Selection,
+}
+
+impl Captured {
+    fn text(text: String, formats: Vec<MimeData>, source: Selection) -> Self {
+        Captured {
+            content: ClipboardContent::Text {
+                display: compact_preview(&text),
+                full: text,
+            },
+            formats,
+            source,
+        }
+    }
+
+    /// Lo que hay ahora en la selección, esté o no en el historial.
+    fn read(selection: Selection) -> Option<Self> {
+        let files = mime::read_files(selection);
+        if let Some((uris, cut)) = mime::parse_files(&files) {
+            return Some(Captured {
+                content: ClipboardContent::Files { uris, cut },
+                formats: files,
+                source: selection,
+            });
+        }
+        let formats = mime::read(selection);
+        let kind = match selection {
+            Selection::Clipboard => LinuxClipboardKind::Clipboard,
+            Selection::Primary => LinuxClipboardKind::Primary,
+        };
+        let mut cb = Clipboard::new().ok()?;
+        if let Ok(text) = cb.get().clipboard(kind).text() {
+            if !text.is_empty() {
+                return Some(Captured::text(text, formats, selection));
+            }
+        }
+        let (texture, raw) = match decode_image(&formats) {
+            Some(image) => image,
+            None => {
+                let img = cb.get().clipboard(kind).image().ok()?;
+                let raw = ImageDataOwned {
+                    width: img.width,
+                    height: img.height,
+                    data: img.bytes.into_owned(),
+                };
+                (
+                    raw_to_texture(raw.width as i32, raw.height as i32, &raw.data),
+                    raw,
+                )
+            }
+        };
+        Some(Captured {
+            content: ClipboardContent::Image { texture, raw },
+            formats,
+            source: selection,
+        })
+    }
+
+    /// Lo que llega por la entrada estándar de `klipBored copy`. `None` si
+    /// no es texto, imagen ni lista de archivos.
+    fn from_input(mime: String, data: Vec<u8>, source: Selection) -> Option<Self> {
+        let input = vec![MimeData { mime, data }];
+        if let Some((uris, cut)) = mime::parse_files(&input) {
+            return Some(Captured {
+                content: ClipboardContent::Files { uris, cut },
+                formats: input,
+                source,
+            });
+        }
+        if input[0].mime.starts_with("image/") {
+            let (texture, raw) = decode_image(&input)?;
+            return Some(Captured {
+                content: ClipboardContent::Image { texture, raw },
+                formats: input,
+                source,
+            });
+        }
+        let text = String::from_utf8(input[0].data.clone()).ok()?;
+        // El texto plano ya lo añade `with_plain_fallback`; HTML, RTF... se
+        // guardan como formato además del texto
+        let formats = if input[0].mime.starts_with("text/plain") {
+            Vec::new()
+        } else {
+            input
+        };
+        Some(Captured::text(text, formats, source))
+    }
+}
+
+/// La primera imagen de `formats` que sepa decodificar GDK.
+fn decode_image(formats: &[MimeData]) -> Option<(gdk::Texture, ImageDataOwned)> {
+    formats.iter().find_map(|f| {
+        if !f.mime.starts_with("image/") {
+            return None;
+        }
+        let texture = gdk::Texture::from_bytes(&glib::Bytes::from(&f.data)).ok()?;
+        let raw = texture_to_raw(&texture);
+        Some((texture, raw))
+    })
+}
+
+/// Para qué se pide la contraseña del historial cuando no hay llavero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnlockMode {
+    Unlock,
+    /// Primera vez: la contraseña con la que se va a cifrar.
+    Create,
+    /// Cambio de clave desde los ajustes.
+    Rekey,
+}
+
+impl UnlockMode {
+    fn description(self) -> &'static str {
+        match self {
+            UnlockMode::Unlock => "Escribe tu contraseña para abrir el historial.",
+            UnlockMode::Create => {
+                "No hay un llavero disponible. Elige una contraseña\npara cifrar el historial."
+            }
+            UnlockMode::Rekey => "Elige la nueva contraseña del historial.",
+        }
+    }
+}
+
+/// Atajo que choca con otras acciones, a la espera de que el usuario
+/// decida qué hacer.
+#[derive(Debug)]
+struct PendingBinding {
+    binding: String,
+    conflicts: Vec<keybinding::Action>,
+    /// Se eligió en el asistente: al terminar se cierra el panel.
+    from_wizard: bool,
 }
+
 struct KlipBoredModel {
     clipboard_entries: FactoryVecDeque<ClipboardEntry>,
-    tracker: Rc<RefCell<ClipboardTracker>>,
+    store: HistoryStore,
+    next_id: u64,
+    tracker: Arc<Mutex<ClipboardTracker>>,
+    settings: Settings,
+    primary_serial: u64,
     setup_done: Rc<RefCell<bool>>,
+    /// El ajuste de ocultar al perder el foco, compartido con las ventanas.
+    hide_on_focus_loss: Rc<Cell<bool>>,
+    query: String,
     current_page: String, // "wizard", "wizard_custom", "clipboard", "settings"
     autostart_enabled: bool,
     current_binding: String,
-    manual_binding: String,
-    binding_status: String, // "ok", "error", "checking"
+    /// Atajo grabado en el asistente, pendiente de guardar.
+    recorded_binding: String,
+    binding_error: String,
+    /// Atajo elegido que ya usa otra acción de GNOME.
+    pending_binding: Option<PendingBinding>,
+    conflict_error: String,
+    /// El atajo va por el portal de atajos globales y no por GNOME.
+    portal_shortcuts: bool,
+    /// Sesión del portal con el atajo registrado.
+    shortcut_session: Option<String>,
+    _shortcut_activated: Option<gio::SignalSubscription>,
+    /// Cómo ha quedado el atajo en el portal, o por qué no se registró.
+    binding_note: String,
+    /// La clave del historial está en el llavero y no sale de una contraseña.
+    uses_keyring: bool,
+    unlock_mode: UnlockMode,
+    unlock_error: String,
+    /// Primer arranque en esta sesión; se mira al desbloquear el historial.
+    new_session: bool,
+    /// No se guarda nada de lo que se copie (desde D-Bus).
+    paused: bool,
+    bus: dbus::Bus,
+    /// Órdenes de la línea de comandos que esperan a que se abra el historial.
+    pending_commands: Vec<(cli::Command, cli::Request)>,
+    /// Lo que toca al historial y llegó mientras se volvía a cifrar.
+    deferred: Vec<KlipBoredMsg>,
+    /// Las órdenes recibidas que aún no se han respondido.
+    commands: cli::Pending,
+    export_range: archive::Range,
+    /// Resultado de la última exportación o importación desde los ajustes.
+    archive_status: String,
+    /// Lo que estaba mal en `config.toml` al leerlo o al importar ajustes.
+    config_error: String,
+    /// Lo que hizo el último restablecimiento, para enseñarlo en el asistente.
+    reset_report: String,
 }
 
 #[derive(Debug)]
 enum KlipBoredMsg {
-    NewItem(ClipboardContent),
+    ClipboardChanged(Selection),
+    PrimarySettled(u64),
+    NewItem(Captured),
     RequestCopy(DynamicIndex),
+    CopyTo(DynamicIndex, CopyTarget),
     DeleteItem(DynamicIndex),
+    /// Desde el teclado: posición y si hay que ocultar la ventana.
+    CopyAt(usize, bool),
+    DeleteAt(usize),
+    TogglePin(DynamicIndex),
+    TogglePasteKeys(DynamicIndex),
+    SetExpiry(DynamicIndex, Expiry),
+    ClearHistory,
+    PruneExpired,
+    /// La sesión gráfica termina (o es otra que la del último arranque).
+    SessionEnded,
+    /// Clave del historial sacada del llavero.
+    KeyReady(vault::Cipher),
+    NeedPassphrase,
+    /// Contraseña y su confirmación (vacía al abrir el historial).
+    SubmitPassphrase(String, String),
+    RekeyHistory,
+    /// Ha terminado de volver a cifrarse el historial.
+    Rekeyed(Result<(), String>),
+    WipeHistory,
+    DBus(dbus::Call, dbus::Request),
+    CommandLine(cli::Command, cli::Request),
+    /// La entrada estándar de `klipBored copy`, ya leída entera.
+    CopyInput(cli::CopyOptions, Vec<u8>, cli::Request),
+    Search(String),
+    SearchActivate,
     WizardAccept,
     WizardShowCustom,
     WizardApplyBinding(String),
     OpenSettings,
     ToggleAutostart(bool),
+    ToggleCapturePrimary(bool),
+    ToggleAutoPaste(bool),
+    SetCopyTarget(CopyTarget),
+    SetSensitiveMode(SensitiveMode),
+    SetMaxEntries(usize),
+    SetMaxTotalMb(u64),
+    SetMaxImageMb(u64),
+    SetTextMaxAge(u64),
+    SetImageMaxAge(u64),
+    SetFilesMaxAge(u64),
+    SetClearSchedule(ClearSchedule),
+    SetTheme(Theme),
+    ToggleHideOnFocusLoss(bool),
+    SetExportRange(archive::Range),
+    /// Pide dónde guardar el archivo y exporta.
+    ChooseExport,
+    ChooseImport,
+    ExportTo(PathBuf),
+    ImportFrom(PathBuf),
+    ChooseSettingsExport,
+    ChooseSettingsImport,
+    ExportSettingsTo(PathBuf),
+    ImportSettingsFrom(PathBuf),
+    /// Deshacer la integración con el escritorio y volver al asistente;
+    /// con `true`, borrando también el historial y los ajustes.
+    Reset(bool),
     BackToClipboard,
-    UpdateManualBinding(String),
+    BindingRecorded(Result<String, String>),
     ApplyManualBinding,
+    /// Quitar el atajo a las acciones con las que choca.
+    OverrideConflict,
+    /// Nuevo atajo para la acción con la que choca.
+    ReassignRecorded(Result<String, String>),
+    ChooseAnotherBinding,
+    /// Si el portal del escritorio tiene atajos globales.
+    PortalShortcuts(bool),
+    /// Resultado de registrar el atajo en el portal: la sesión y el atajo.
+    ShortcutBound(Result<(String, String), String>),
 }
 
 #[relm4::component]
 impl SimpleComponent for KlipBoredModel {
-    type Init = ();
+    /// Dónde dejar `hide_on_focus_loss` para las ventanas (ver `main`).
+    type Init = Rc<Cell<bool>>;
     type Input = KlipBoredMsg;
     type Output = ();
 
@@ -291,15 +1058,44 @@ impl SimpleComponent for KlipBoredModel {
                     set_show_end_title_buttons: true,
 
                     #[wrap(Some)]
-                    set_title_widget = &gtk::Label {
-                        set_label: "klipBored",
-                        add_css_class: "header-title",
+                    set_title_widget = &gtk::Box {
+                        gtk::Label {
+                            set_label: "klipBored",
+                            add_css_class: "header-title",
+                            #[watch]
+                            set_visible: model.current_page != "clipboard",
+                        },
+
+                        #[name = "search_entry"]
+                        gtk::SearchEntry {
+                            #[watch]
+                            set_placeholder_text: Some(if model.paused {
+                                "En pausa: no se guarda lo que copies"
+                            } else {
+                                "Buscar en el historial"
+                            }),
+                            set_hexpand: true,
+                            #[watch]
+                            set_visible: model.current_page == "clipboard",
+                            connect_search_changed[sender] => move |entry| {
+                                sender.input(KlipBoredMsg::Search(entry.text().to_string()));
+                            },
+                            connect_activate[sender] => move |_| {
+                                sender.input(KlipBoredMsg::SearchActivate);
+                            },
+                            connect_stop_search => move |entry| {
+                                entry.set_text("");
+                            },
+                        },
                     },
 
                     pack_start = &gtk::Button {
                         set_icon_name: "go-previous-symbolic",
                         #[watch]
-                        set_visible: model.current_page == "settings" || model.current_page == "wizard_custom",
+                        set_visible: model.current_page == "settings"
+                            || model.current_page == "wizard_custom"
+                            || model.current_page == "binding_conflict"
+                            || (model.current_page == "unlock" && model.unlock_mode == UnlockMode::Rekey),
                         connect_clicked[sender] => move |_| {
                             sender.input(KlipBoredMsg::BackToClipboard);
                         }
@@ -339,7 +1135,7 @@ impl SimpleComponent for KlipBoredModel {
                         gtk::Picture {
                             set_paintable: Some(&app_icon_paintable()),
                             set_can_shrink: true,
-                            set_keep_aspect_ratio: true,
+                            set_content_fit: gtk::ContentFit::Contain,
                             set_width_request: 64,
                             set_height_request: 64,
                         },
@@ -356,6 +1152,16 @@ impl SimpleComponent for KlipBoredModel {
                             add_css_class: "wizard-description",
                         },
 
+                        gtk::Label {
+                            #[watch]
+                            set_label: &model.reset_report,
+                            set_justify: gtk::Justification::Center,
+                            set_wrap: true,
+                            add_css_class: "wizard-description",
+                            #[watch]
+                            set_visible: !model.reset_report.is_empty(),
+                        },
+
                         gtk::Box {
                             set_orientation: gtk::Orientation::Vertical,
                             set_spacing: 10,
@@ -396,7 +1202,7 @@ impl SimpleComponent for KlipBoredModel {
                         gtk::Picture {
                             set_paintable: Some(&app_icon_paintable()),
                             set_can_shrink: true,
-                            set_keep_aspect_ratio: true,
+                            set_content_fit: gtk::ContentFit::Contain,
                             set_width_request: 48,
                             set_height_request: 48,
                         },
@@ -425,7 +1231,7 @@ impl SimpleComponent for KlipBoredModel {
                                     set_orientation: gtk::Orientation::Horizontal,
                                     set_spacing: 12,
                                     set_halign: gtk::Align::Center,
-                                    gtk::Label { set_label: "Win + V", add_css_class: "shortcut-key" },
+                                    gtk::Label { set_label: &shortcut::label("<Super>v"), add_css_class: "shortcut-key" },
                                 },
                                 connect_clicked[sender] => move |_| {
                                     sender.input(KlipBoredMsg::WizardApplyBinding("<Super>v".to_string()));
@@ -439,7 +1245,7 @@ impl SimpleComponent for KlipBoredModel {
                                     set_orientation: gtk::Orientation::Horizontal,
                                     set_spacing: 12,
                                     set_halign: gtk::Align::Center,
-                                    gtk::Label { set_label: "Ctrl + Shift + V", add_css_class: "shortcut-key" },
+                                    gtk::Label { set_label: &shortcut::label("<Control><Shift>v"), add_css_class: "shortcut-key" },
                                 },
                                 connect_clicked[sender] => move |_| {
                                     sender.input(KlipBoredMsg::WizardApplyBinding("<Control><Shift>v".to_string()));
@@ -452,7 +1258,7 @@ impl SimpleComponent for KlipBoredModel {
                             },
 
                             gtk::Label {
-                                set_label: "O introduce uno manualmente:",
+                                set_label: "O graba el tuyo:",
                                 add_css_class: "wizard-description",
                             },
 
@@ -461,21 +1267,16 @@ impl SimpleComponent for KlipBoredModel {
                                 set_spacing: 8,
                                 add_css_class: "manual-entry-box",
 
-                                gtk::Entry {
-                                    set_placeholder_text: Some("<Super>x, <Control>v..."),
+                                #[local_ref]
+                                binding_recorder -> gtk::Button {
                                     set_hexpand: true,
-                                    #[watch]
-                                    set_text: &model.manual_binding,
-                                    connect_changed[sender] => move |e| {
-                                        sender.input(KlipBoredMsg::UpdateManualBinding(e.text().to_string()));
-                                    },
                                 },
 
                                 gtk::Button {
                                     set_label: "Guardar",
                                     add_css_class: "wizard-btn-primary",
                                     #[watch]
-                                    set_sensitive: !model.manual_binding.is_empty() && model.binding_status != "error",
+                                    set_sensitive: !model.recorded_binding.is_empty(),
                                     connect_clicked[sender] => move |_| {
                                         sender.input(KlipBoredMsg::ApplyManualBinding);
                                     }
@@ -484,77 +1285,615 @@ impl SimpleComponent for KlipBoredModel {
 
                             gtk::Label {
                                 #[watch]
-                                set_label: if model.binding_status == "error" { "Atajo inválido o incompleto" } else { "" },
+                                set_label: &model.binding_error,
+                                set_wrap: true,
                                 add_css_class: "error-label",
                                 #[watch]
-                                set_visible: model.binding_status == "error",
+                                set_visible: !model.binding_error.is_empty(),
                             }
                         },
                     },
 
-                    // --- Página del Clipboard ---
-                    add_named[Some("clipboard")] = &gtk::ScrolledWindow {
-                        set_vexpand: true,
-                        #[local_ref]
-                        list_box -> gtk::ListBox {
-                            add_css_class: "content-list",
-                        }
-                    },
-
-                    // --- Página de Ajustes ---
-                    add_named[Some("settings")] = &gtk::Box {
+                    // --- El atajo ya lo usa otra acción ---
+                    add_named[Some("binding_conflict")] = &gtk::Box {
                         set_orientation: gtk::Orientation::Vertical,
+                        set_valign: gtk::Align::Center,
+                        set_halign: gtk::Align::Center,
                         set_spacing: 20,
-                        set_margin_all: 24,
+                        set_margin_start: 32,
+                        set_margin_end: 32,
+                        set_margin_top: 24,
+                        set_margin_bottom: 32,
+
+                        gtk::Label {
+                            set_label: "Ese atajo ya está en uso",
+                            add_css_class: "wizard-title",
+                        },
 
                         gtk::Label {
-                            set_label: "Ajustes",
-                            set_halign: gtk::Align::Start,
-                            add_css_class: "settings-section-title",
+                            #[watch]
+                            set_label: &model.conflict_summary(),
+                            set_justify: gtk::Justification::Center,
+                            set_wrap: true,
+                            add_css_class: "wizard-description",
                         },
 
-                        gtk::ListBox {
-                            add_css_class: "boxed-list",
-                            set_selection_mode: gtk::SelectionMode::None,
+                        gtk::Box {
+                            set_orientation: gtk::Orientation::Vertical,
+                            set_spacing: 8,
+                            set_halign: gtk::Align::Center,
 
-                            adw::ActionRow {
-                                set_title: "Arrancar al inicio",
-                                set_subtitle: "Abrir klipBored al iniciar sesión",
-                                add_suffix = &gtk::Switch {
-                                    set_valign: gtk::Align::Center,
-                                    #[watch]
-                                    set_active: model.autostart_enabled,
-                                    connect_state_set[sender] => move |_, state| {
-                                        sender.input(KlipBoredMsg::ToggleAutostart(state));
-                                        glib::Propagation::Proceed
-                                    }
+                            gtk::Button {
+                                set_label: "Quitárselo y usarlo en klipBored",
+                                add_css_class: "wizard-btn-primary",
+                                connect_clicked[sender] => move |_| {
+                                    sender.input(KlipBoredMsg::OverrideConflict);
                                 }
                             },
 
-                            adw::ActionRow {
-                                set_title: "Atajo de teclado",
+                            gtk::Label {
+                                set_label: "O dale otro atajo a esa acción:",
+                                add_css_class: "wizard-description",
                                 #[watch]
-                                set_subtitle: &format!("Actual: {}", model.current_binding.replace("<Super>", "Win + ").replace("<Control>", "Ctrl + ").replace("<Shift>", "Shift + ")),
+                                set_visible: model.can_reassign(),
+                            },
 
-                                add_suffix = &gtk::Button {
-                                    set_label: "Personalizar",
-                                    add_css_class: "wizard-btn-secondary",
-                                    set_valign: gtk::Align::Center,
-                                    connect_clicked[sender] => move |_| {
-                                        sender.input(KlipBoredMsg::WizardShowCustom);
-                                    }
+                            #[local_ref]
+                            reassign_recorder -> gtk::Button {
+                                #[watch]
+                                set_visible: model.can_reassign(),
+                            },
+
+                            gtk::Button {
+                                set_label: "Elegir otro atajo",
+                                add_css_class: "wizard-btn-secondary",
+                                connect_clicked[sender] => move |_| {
+                                    sender.input(KlipBoredMsg::ChooseAnotherBinding);
                                 }
+                            },
+
+                            gtk::Label {
+                                #[watch]
+                                set_label: &model.conflict_error,
+                                set_wrap: true,
+                                add_css_class: "error-label",
+                                #[watch]
+                                set_visible: !model.conflict_error.is_empty(),
                             }
                         },
+                    },
 
-                        gtk::Box {
-                            set_vexpand: true,
+                    // --- Contraseña del historial cifrado ---
+                    add_named[Some("unlock")] = &gtk::Box {
+                        set_orientation: gtk::Orientation::Vertical,
+                        set_valign: gtk::Align::Center,
+                        set_halign: gtk::Align::Center,
+                        set_spacing: 20,
+                        set_margin_start: 32,
+                        set_margin_end: 32,
+                        set_margin_top: 24,
+                        set_margin_bottom: 32,
+
+                        gtk::Image {
+                            set_icon_name: Some("channel-secure-symbolic"),
+                            set_pixel_size: 48,
                         },
 
                         gtk::Label {
-                            set_label: "klipBored v0.1.0",
-                            add_css_class: "version-label",
-                        }
+                            set_label: "Historial cifrado",
+                            add_css_class: "wizard-title",
+                        },
+
+                        gtk::Label {
+                            #[watch]
+                            set_label: model.unlock_mode.description(),
+                            set_justify: gtk::Justification::Center,
+                            set_wrap: true,
+                            add_css_class: "wizard-description",
+                        },
+
+                        gtk::Box {
+                            set_orientation: gtk::Orientation::Horizontal,
+                            set_spacing: 8,
+                            add_css_class: "manual-entry-box",
+
+                            gtk::Box {
+                                set_orientation: gtk::Orientation::Vertical,
+                                set_spacing: 8,
+                                set_hexpand: true,
+
+                                #[name = "passphrase_entry"]
+                                gtk::PasswordEntry {
+                                    set_show_peek_icon: true,
+                                },
+
+                                // Al elegir una contraseña nueva, una errata
+                                // dejaría el historial sin abrir
+                                #[name = "passphrase_confirm"]
+                                gtk::PasswordEntry {
+                                    set_show_peek_icon: true,
+                                    set_placeholder_text: Some("Repite la contraseña"),
+                                    #[watch]
+                                    set_visible: model.unlock_mode != UnlockMode::Unlock,
+                                },
+                            },
+
+                            #[name = "unlock_button"]
+                            gtk::Button {
+                                add_css_class: "wizard-btn-primary",
+                                #[watch]
+                                set_label: if model.unlock_mode == UnlockMode::Unlock { "Abrir" } else { "Guardar" },
+                            },
+                        },
+
+                        gtk::Label {
+                            #[watch]
+                            set_label: &model.unlock_error,
+                            set_wrap: true,
+                            set_justify: gtk::Justification::Center,
+                            add_css_class: "error-label",
+                            #[watch]
+                            set_visible: !model.unlock_error.is_empty(),
+                        },
+
+                        gtk::Button {
+                            set_label: "Borrar el historial y empezar de nuevo",
+                            add_css_class: "wizard-btn-secondary",
+                            #[watch]
+                            set_visible: model.unlock_mode == UnlockMode::Unlock,
+                            connect_clicked[sender] => move |_| {
+                                confirm_wipe(&sender);
+                            }
+                        },
+                    },
+
+                    // --- Página del Clipboard ---
+                    add_named[Some("clipboard")] = &gtk::ScrolledWindow {
+                        set_vexpand: true,
+                        #[local_ref]
+                        list_box -> gtk::ListBox {
+                            add_css_class: "content-list",
+                        }
+                    },
+
+                    // --- Página de Ajustes ---
+                    add_named[Some("settings")] = &gtk::ScrolledWindow {
+                        set_hscrollbar_policy: gtk::PolicyType::Never,
+
+                        gtk::Box {
+                            set_orientation: gtk::Orientation::Vertical,
+                            set_spacing: 20,
+                            set_margin_all: 24,
+
+                            gtk::Label {
+                                set_label: "Ajustes",
+                                set_halign: gtk::Align::Start,
+                                add_css_class: "settings-section-title",
+                            },
+
+                            gtk::Label {
+                                #[watch]
+                                set_label: &model.config_error,
+                                set_halign: gtk::Align::Start,
+                                set_xalign: 0.0,
+                                set_wrap: true,
+                                add_css_class: "error-label",
+                                #[watch]
+                                set_visible: !model.config_error.is_empty(),
+                            },
+
+                            gtk::ListBox {
+                                add_css_class: "boxed-list",
+                                set_selection_mode: gtk::SelectionMode::None,
+
+                                adw::ActionRow {
+                                    set_title: "Arrancar al inicio",
+                                    set_subtitle: "Abrir klipBored al iniciar sesión",
+                                    add_suffix = &gtk::Switch {
+                                        set_valign: gtk::Align::Center,
+                                        #[watch]
+                                        set_active: model.autostart_enabled,
+                                        connect_state_set[sender] => move |_, state| {
+                                            sender.input(KlipBoredMsg::ToggleAutostart(state));
+                                            glib::Propagation::Proceed
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Selección primaria",
+                                    set_subtitle: "Guardar también el texto seleccionado con el ratón",
+                                    add_suffix = &gtk::Switch {
+                                        set_valign: gtk::Align::Center,
+                                        #[watch]
+                                        set_active: model.settings.capture.primary,
+                                        connect_state_set[sender] => move |_, state| {
+                                            sender.input(KlipBoredMsg::ToggleCapturePrimary(state));
+                                            glib::Propagation::Proceed
+                                        }
+                                    }
+                                },
+
+                                adw::ComboRow {
+                                    set_title: "Copiar en",
+                                    set_subtitle: "Dónde dejar una entrada al recuperarla",
+                                    set_model: Some(&gtk::StringList::new(
+                                        &CopyTarget::ALL.map(CopyTarget::label),
+                                    )),
+                                    #[watch]
+                                    set_selected: model.settings.window.copy_target as u32,
+                                    connect_selected_notify[sender] => move |row| {
+                                        if let Some(target) = CopyTarget::ALL.get(row.selected() as usize) {
+                                            sender.input(KlipBoredMsg::SetCopyTarget(*target));
+                                        }
+                                    }
+                                },
+
+                                adw::ComboRow {
+                                    set_title: "Contenido sensible",
+                                    set_subtitle: "Claves privadas, tokens y tarjetas detectados al copiar",
+                                    set_model: Some(&gtk::StringList::new(
+                                        &SensitiveMode::ALL.map(SensitiveMode::label),
+                                    )),
+                                    #[watch]
+                                    set_selected: model.settings.privacy.sensitive as u32,
+                                    connect_selected_notify[sender] => move |row| {
+                                        if let Some(mode) = SensitiveMode::ALL.get(row.selected() as usize) {
+                                            sender.input(KlipBoredMsg::SetSensitiveMode(*mode));
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Pegar automáticamente",
+                                    set_subtitle: "Pegar la entrada en la ventana anterior al recuperarla",
+                                    add_suffix = &gtk::Switch {
+                                        set_valign: gtk::Align::Center,
+                                        #[watch]
+                                        set_active: model.settings.window.auto_paste,
+                                        connect_state_set[sender] => move |_, state| {
+                                            sender.input(KlipBoredMsg::ToggleAutoPaste(state));
+                                            glib::Propagation::Proceed
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Ocultar al perder el foco",
+                                    set_subtitle: "Cerrar el panel al pasar a otra ventana",
+                                    add_suffix = &gtk::Switch {
+                                        set_valign: gtk::Align::Center,
+                                        #[watch]
+                                        set_active: model.settings.window.hide_on_focus_loss,
+                                        connect_state_set[sender] => move |_, state| {
+                                            sender.input(KlipBoredMsg::ToggleHideOnFocusLoss(state));
+                                            glib::Propagation::Proceed
+                                        }
+                                    }
+                                },
+
+                                adw::ComboRow {
+                                    set_title: "Tema",
+                                    set_model: Some(&gtk::StringList::new(
+                                        &Theme::ALL.map(Theme::label),
+                                    )),
+                                    #[watch]
+                                    set_selected: model.settings.window.theme as u32,
+                                    connect_selected_notify[sender] => move |row| {
+                                        if let Some(theme) = Theme::ALL.get(row.selected() as usize) {
+                                            sender.input(KlipBoredMsg::SetTheme(*theme));
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Atajo de teclado",
+                                    #[watch]
+                                    set_subtitle: &model.binding_summary(),
+
+                                    add_suffix = &gtk::Button {
+                                        set_label: "Personalizar",
+                                        add_css_class: "wizard-btn-secondary",
+                                        set_valign: gtk::Align::Center,
+                                        connect_clicked[sender] => move |_| {
+                                            sender.input(KlipBoredMsg::WizardShowCustom);
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Atajos del panel",
+                                    set_subtitle: "Navegar y copiar sin el ratón (Ctrl + ?)",
+                                    add_suffix = &gtk::Button {
+                                        set_label: "Ver",
+                                        add_css_class: "wizard-btn-secondary",
+                                        set_valign: gtk::Align::Center,
+                                        set_action_name: Some("win.show-help-overlay"),
+                                    }
+                                }
+                            },
+
+                            gtk::Label {
+                                set_label: "Historial",
+                                set_halign: gtk::Align::Start,
+                                add_css_class: "settings-section-title",
+                            },
+
+                            gtk::ListBox {
+                                add_css_class: "boxed-list",
+                                set_selection_mode: gtk::SelectionMode::None,
+
+                                adw::ActionRow {
+                                    set_title: "Uso actual",
+                                    #[watch]
+                                    set_subtitle: &model.usage_summary(),
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Borrar historial",
+                                    set_subtitle: "Las entradas fijadas se conservan",
+                                    add_suffix = &gtk::Button {
+                                        set_label: "Borrar",
+                                        add_css_class: "destructive-action",
+                                        set_valign: gtk::Align::Center,
+                                        connect_clicked[sender] => move |_| {
+                                            confirm(
+                                                &sender,
+                                                "¿Borrar el historial?",
+                                                "Se borran todas las entradas menos las fijadas. No se puede deshacer.",
+                                                "Borrar",
+                                                KlipBoredMsg::ClearHistory,
+                                            );
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Clave de cifrado",
+                                    #[watch]
+                                    set_subtitle: if model.uses_keyring {
+                                        "Guardada en el llavero del sistema"
+                                    } else {
+                                        "Sale de tu contraseña"
+                                    },
+                                    add_suffix = &gtk::Button {
+                                        set_label: "Cambiar",
+                                        add_css_class: "wizard-btn-secondary",
+                                        set_valign: gtk::Align::Center,
+                                        connect_clicked[sender] => move |_| {
+                                            sender.input(KlipBoredMsg::RekeyHistory);
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Borrar todo y olvidar la clave",
+                                    set_subtitle: "También las entradas fijadas",
+                                    add_suffix = &gtk::Button {
+                                        set_label: "Borrar todo",
+                                        add_css_class: "destructive-action",
+                                        set_valign: gtk::Align::Center,
+                                        connect_clicked[sender] => move |_| {
+                                            confirm_wipe(&sender);
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Máximo de entradas",
+                                    add_suffix = &gtk::SpinButton::with_range(10.0, 10000.0, 10.0) {
+                                        set_valign: gtk::Align::Center,
+                                        #[watch]
+                                        set_value: model.settings.history.max_entries as f64,
+                                        connect_value_changed[sender] => move |spin| {
+                                            sender.input(KlipBoredMsg::SetMaxEntries(spin.value() as usize));
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Tamaño máximo (MB)",
+                                    set_subtitle: "Todo el historial",
+                                    add_suffix = &gtk::SpinButton::with_range(16.0, 8192.0, 16.0) {
+                                        set_valign: gtk::Align::Center,
+                                        #[watch]
+                                        set_value: model.settings.history.max_total_mb as f64,
+                                        connect_value_changed[sender] => move |spin| {
+                                            sender.input(KlipBoredMsg::SetMaxTotalMb(spin.value() as u64));
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Máximo para imágenes (MB)",
+                                    set_subtitle: "Se guardan en PNG y cuentan dentro del tamaño máximo",
+                                    add_suffix = &gtk::SpinButton::with_range(16.0, 8192.0, 16.0) {
+                                        set_valign: gtk::Align::Center,
+                                        #[watch]
+                                        set_range: (16.0, model.settings.history.max_total_mb as f64),
+                                        #[watch]
+                                        set_value: model.settings.history.max_image_mb as f64,
+                                        connect_value_changed[sender] => move |spin| {
+                                            sender.input(KlipBoredMsg::SetMaxImageMb(spin.value() as u64));
+                                        }
+                                    }
+                                },
+
+                                adw::ComboRow {
+                                    set_title: "Borrar automáticamente",
+                                    set_subtitle: "Las entradas fijadas se conservan",
+                                    set_model: Some(&gtk::StringList::new(
+                                        &ClearSchedule::ALL.map(ClearSchedule::label),
+                                    )),
+                                    #[watch]
+                                    set_selected: model.settings.history.clear_schedule as u32,
+                                    connect_selected_notify[sender] => move |row| {
+                                        if let Some(schedule) = ClearSchedule::ALL.get(row.selected() as usize) {
+                                            sender.input(KlipBoredMsg::SetClearSchedule(*schedule));
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Caducidad de textos (días)",
+                                    set_subtitle: "0 para no caducar nunca",
+                                    add_suffix = &gtk::SpinButton::with_range(0.0, 3650.0, 1.0) {
+                                        set_valign: gtk::Align::Center,
+                                        #[watch]
+                                        set_value: model.settings.history.text_max_age_days as f64,
+                                        connect_value_changed[sender] => move |spin| {
+                                            sender.input(KlipBoredMsg::SetTextMaxAge(spin.value() as u64));
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Caducidad de imágenes (días)",
+                                    set_subtitle: "0 para no caducar nunca",
+                                    add_suffix = &gtk::SpinButton::with_range(0.0, 3650.0, 1.0) {
+                                        set_valign: gtk::Align::Center,
+                                        #[watch]
+                                        set_value: model.settings.history.image_max_age_days as f64,
+                                        connect_value_changed[sender] => move |spin| {
+                                            sender.input(KlipBoredMsg::SetImageMaxAge(spin.value() as u64));
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Caducidad de archivos (días)",
+                                    set_subtitle: "0 para no caducar nunca",
+                                    add_suffix = &gtk::SpinButton::with_range(0.0, 3650.0, 1.0) {
+                                        set_valign: gtk::Align::Center,
+                                        #[watch]
+                                        set_value: model.settings.history.files_max_age_days as f64,
+                                        connect_value_changed[sender] => move |spin| {
+                                            sender.input(KlipBoredMsg::SetFilesMaxAge(spin.value() as u64));
+                                        }
+                                    }
+                                },
+                            },
+
+                            gtk::Label {
+                                set_label: "Copia de seguridad",
+                                set_halign: gtk::Align::Start,
+                                add_css_class: "settings-section-title",
+                            },
+
+                            gtk::ListBox {
+                                add_css_class: "boxed-list",
+                                set_selection_mode: gtk::SelectionMode::None,
+
+                                adw::ComboRow {
+                                    set_title: "Qué exportar",
+                                    set_model: Some(&gtk::StringList::new(
+                                        &archive::Range::ALL.map(archive::Range::label),
+                                    )),
+                                    connect_selected_notify[sender] => move |row| {
+                                        if let Some(range) = archive::Range::ALL.get(row.selected() as usize) {
+                                            sender.input(KlipBoredMsg::SetExportRange(*range));
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Exportar historial",
+                                    set_subtitle: "Archivo .tar sin cifrar, para llevarlo a otro equipo",
+                                    add_suffix = &gtk::Button {
+                                        set_label: "Exportar…",
+                                        add_css_class: "wizard-btn-secondary",
+                                        set_valign: gtk::Align::Center,
+                                        connect_clicked[sender] => move |_| {
+                                            sender.input(KlipBoredMsg::ChooseExport);
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Importar historial",
+                                    #[watch]
+                                    set_subtitle: if model.archive_status.is_empty() {
+                                        "Se añade lo que no esté ya en el historial"
+                                    } else {
+                                        model.archive_status.as_str()
+                                    },
+                                    add_suffix = &gtk::Button {
+                                        set_label: "Importar…",
+                                        add_css_class: "wizard-btn-secondary",
+                                        set_valign: gtk::Align::Center,
+                                        connect_clicked[sender] => move |_| {
+                                            sender.input(KlipBoredMsg::ChooseImport);
+                                        }
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Exportar o importar ajustes",
+                                    set_subtitle: "config.toml con todas las preferencias y el atajo",
+                                    add_suffix = &gtk::Box {
+                                        set_spacing: 6,
+                                        set_valign: gtk::Align::Center,
+
+                                        gtk::Button {
+                                            set_label: "Exportar…",
+                                            add_css_class: "wizard-btn-secondary",
+                                            connect_clicked[sender] => move |_| {
+                                                sender.input(KlipBoredMsg::ChooseSettingsExport);
+                                            }
+                                        },
+
+                                        gtk::Button {
+                                            set_label: "Importar…",
+                                            add_css_class: "wizard-btn-secondary",
+                                            connect_clicked[sender] => move |_| {
+                                                sender.input(KlipBoredMsg::ChooseSettingsImport);
+                                            }
+                                        },
+                                    }
+                                },
+
+                                adw::ActionRow {
+                                    set_title: "Restablecer klipBored",
+                                    set_subtitle: "Quita el atajo y el inicio automático, devuelve a GNOME los atajos que se le quitaron y vuelve al asistente",
+                                    add_suffix = &gtk::Box {
+                                        set_spacing: 6,
+                                        set_valign: gtk::Align::Center,
+
+                                        gtk::Button {
+                                            set_label: "Restablecer",
+                                            add_css_class: "wizard-btn-secondary",
+                                            connect_clicked[sender] => move |_| {
+                                                sender.input(KlipBoredMsg::Reset(false));
+                                            }
+                                        },
+
+                                        gtk::Button {
+                                            set_label: "Y borrar todo",
+                                            set_tooltip_text: Some("También el historial, su clave y los ajustes"),
+                                            add_css_class: "destructive-action",
+                                            connect_clicked[sender] => move |_| {
+                                                confirm(
+                                                    &sender,
+                                                    "¿Restablecer y borrar todo?",
+                                                    "Además de deshacer la integración con el escritorio, se borran el historial, su clave y los ajustes. No se puede deshacer.",
+                                                    "Borrar todo",
+                                                    KlipBoredMsg::Reset(true),
+                                                );
+                                            }
+                                        },
+                                    }
+                                },
+                            },
+
+                            gtk::Box {
+                                set_vexpand: true,
+                            },
+
+                            gtk::Label {
+                                set_label: "klipBored v0.1.0",
+                                add_css_class: "version-label",
+                            }
+                        }
                     },
                 },
 
@@ -563,19 +1902,22 @@ impl SimpleComponent for KlipBoredModel {
     }
 
     fn init(
-        _: Self::Init,
+        hide_on_focus_loss: Self::Init,
         root: Self::Root,
         sender: ComponentSender<Self>,
     ) -> ComponentParts<Self> {
-        let needs_setup = !has_keybinding();
+        let (settings, config_error) = Settings::load();
+        hide_on_focus_loss.set(settings.window.hide_on_focus_loss);
+        apply_theme(settings.window.theme);
+        let needs_setup = settings.keybinding.trim().is_empty();
         let setup_done = Rc::new(RefCell::new(!needs_setup));
         let root_ref = root.clone();
 
-        let tracker = Rc::new(RefCell::new(ClipboardTracker {
-            last_text: String::new(),
-            last_img_hash: 0,
-            last_own_copy: Instant::now() - Duration::from_secs(5),
-        }));
+        // El historial está cifrado; se carga cuando llegue la clave
+        let store = HistoryStore::open();
+        find_key(sender.clone(), false);
+
+        let tracker = Arc::default();
 
         let clipboard_entries =
             FactoryVecDeque::builder()
@@ -583,51 +1925,85 @@ impl SimpleComponent for KlipBoredModel {
                 .forward(sender.input_sender(), |output| match output {
                     ClipboardEntryOutput::RequestCopy(index) => KlipBoredMsg::RequestCopy(index),
                     ClipboardEntryOutput::DeleteItem(index) => KlipBoredMsg::DeleteItem(index),
+                    ClipboardEntryOutput::TogglePin(index) => KlipBoredMsg::TogglePin(index),
+                    ClipboardEntryOutput::TogglePasteKeys(index) => {
+                        KlipBoredMsg::TogglePasteKeys(index)
+                    }
+                    ClipboardEntryOutput::SetExpiry(index, expiry) => {
+                        KlipBoredMsg::SetExpiry(index, expiry)
+                    }
+                    ClipboardEntryOutput::CopyTo(index, target) => {
+                        KlipBoredMsg::CopyTo(index, target)
+                    }
                 });
 
-        // Polling del clipboard: solo activo si setup_done es true
-        let tracker_loop = tracker.clone();
-        let setup_done_loop = setup_done.clone();
-        let s_clone = sender.clone();
-        glib::timeout_add_local(Duration::from_millis(800), move || {
-            if !*setup_done_loop.borrow() {
-                return glib::ControlFlow::Continue;
-            }
+        // Avisos del sistema cuando cambia el portapapeles; si no hay forma
+        // de recibirlos, volvemos a sondearlo cada 800 ms.
+        let s_watch = sender.clone();
+        let backend = watcher::spawn(move |selection| {
+            s_watch.input(KlipBoredMsg::ClipboardChanged(selection))
+        });
+        if backend == watcher::Backend::Polling {
+            let s_poll = sender.clone();
+            glib::timeout_add_local(Duration::from_millis(800), move || {
+                s_poll.input(KlipBoredMsg::ClipboardChanged(Selection::Clipboard));
+                s_poll.input(KlipBoredMsg::ClipboardChanged(Selection::Primary));
+                glib::ControlFlow::Continue
+            });
+        }
 
-            let mut state = tracker_loop.borrow_mut();
-            if state.last_own_copy.elapsed() < Duration::from_millis(1500) {
-                return glib::ControlFlow::Continue;
-            }
-            if let Ok(mut cb) = Clipboard::new() {
-                if let Ok(text) = cb.get_text() {
-                    if !text.is_empty() && text != state.last_text {
-                        state.last_text = text.clone();
-                        s_clone.input(KlipBoredMsg::NewItem(ClipboardContent::Text {
-                            full: text.clone(),
-                            display: compact_preview(&text),
-                        }));
-                        return glib::ControlFlow::Continue;
-                    }
+        // Caducidades y borrado a medianoche; lo caducado mientras estábamos
+        // cerrados se quita al cargar el historial
+        let s_prune = sender.clone();
+        glib::timeout_add_seconds_local(PRUNE_INTERVAL, move || {
+            s_prune.input(KlipBoredMsg::PruneExpired);
+            glib::ControlFlow::Continue
+        });
+        // Borrado al cerrar sesión: al terminar la sesión y, por si no nos dio
+        // tiempo, al arrancar en una sesión nueva
+        let new_session = store.is_new_session();
+        let app = relm4::main_application();
+        let s_session = sender.clone();
+        app.connect_query_end(move |_| s_session.input(KlipBoredMsg::SessionEnded));
+
+        // Donde hay portal de atajos globales, el atajo lo avisa a esta
+        // instancia. Preguntarle bloquea, así que se hace en otro hilo
+        let s_portal = sender.clone();
+        std::thread::spawn(move || {
+            s_portal.input(KlipBoredMsg::PortalShortcuts(global_shortcut::available()))
+        });
+
+        let s_bus = sender.clone();
+        let bus = dbus::Bus::export(&app, move |call, request| {
+            s_bus.input(KlipBoredMsg::DBus(call, request))
+        });
+
+        // `klipBored <orden>`, en esta instancia o reenviada desde otra
+        let s_cli = sender.clone();
+        let commands = cli::Pending::default();
+        let pending = commands.clone();
+        app.connect_command_line(move |_, cmdline| {
+            let args: Vec<String> = cmdline
+                .arguments()
+                .iter()
+                .skip(1)
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect();
+            match cli::Command::parse(&args) {
+                Ok(command) => {
+                    let request = pending.add(cmdline.clone());
+                    s_cli.input(KlipBoredMsg::CommandLine(command, request));
+                    glib::ExitCode::from(cli::EXIT_OK)
                 }
-                if let Ok(img) = cb.get_image() {
-                    let h = calculate_hash(&img.bytes);
-                    if img.bytes.len() > 0 && h != state.last_img_hash {
-                        state.last_img_hash = h;
-                        let owned = ImageDataOwned {
-                            width: img.width,
-                            height: img.height,
-                            data: img.bytes.into_owned(),
-                        };
-                        let tex =
-                            raw_to_texture(owned.width as i32, owned.height as i32, &owned.data);
-                        s_clone.input(KlipBoredMsg::NewItem(ClipboardContent::Image {
-                            texture: tex,
-                            raw: owned,
-                        }));
-                    }
+                Err(e) => {
+                    print_to(
+                        cmdline,
+                        &format!("klipBored: {}\n\n{}", e, cli::USAGE),
+                        true,
+                    );
+                    glib::ExitCode::from(cli::EXIT_USAGE)
                 }
             }
-            glib::ControlFlow::Continue
         });
 
         let current_page = if needs_setup {
@@ -637,18 +2013,120 @@ impl SimpleComponent for KlipBoredModel {
         };
         let model = KlipBoredModel {
             clipboard_entries,
+            store,
+            next_id: 1,
             tracker,
+            current_binding: if needs_setup {
+                DEFAULT_BINDING.to_string()
+            } else {
+                settings.keybinding.clone()
+            },
+            settings,
+            primary_serial: 0,
+            query: String::new(),
             setup_done: setup_done.clone(),
+            hide_on_focus_loss,
             current_page,
             autostart_enabled: is_autostart_enabled(),
-            current_binding: get_keybinding(),
-            manual_binding: String::new(),
-            binding_status: "ok".to_string(),
+            recorded_binding: String::new(),
+            binding_error: String::new(),
+            pending_binding: None,
+            conflict_error: String::new(),
+            portal_shortcuts: false,
+            shortcut_session: None,
+            _shortcut_activated: None,
+            binding_note: String::new(),
+            uses_keyring: false,
+            unlock_mode: UnlockMode::Unlock,
+            unlock_error: String::new(),
+            new_session,
+            paused: false,
+            bus,
+            pending_commands: Vec::new(),
+            deferred: Vec::new(),
+            commands,
+            export_range: archive::Range::All,
+            archive_status: String::new(),
+            config_error: config_error.unwrap_or_default(),
+            reset_report: String::new(),
         };
 
         let list_box = model.clipboard_entries.widget();
+        let s_record = sender.clone();
+        let binding_recorder = shortcut::recorder(move |res| {
+            s_record.input(KlipBoredMsg::BindingRecorded(res));
+        });
+        let s_reassign = sender.clone();
+        let reassign_recorder = shortcut::recorder(move |res| {
+            s_reassign.input(KlipBoredMsg::ReassignRecorded(res));
+        });
         let widgets = view_output!();
 
+        // Cabeceras "Fijadas" / "Recientes" cuando hay entradas fijadas
+        list_box.set_header_func(|row, before| {
+            let is_pinned = |row: &gtk::ListBoxRow| {
+                row.child()
+                    .is_some_and(|child| child.has_css_class("pinned"))
+            };
+            let title = match before {
+                None if is_pinned(row) => Some("Fijadas"),
+                Some(prev) if is_pinned(prev) && !is_pinned(row) => Some("Recientes"),
+                _ => None,
+            };
+            let header = title.map(|title| {
+                let label = gtk::Label::new(Some(title));
+                label.set_xalign(0.0);
+                label.add_css_class("list-section-header");
+                label
+            });
+            row.set_header(header.as_ref());
+        });
+        list_box.set_filter_func(|row| {
+            row.child()
+                .is_none_or(|child| !child.has_css_class("filtered-out"))
+        });
+
+        // La contraseña no se guarda en el modelo; se manda y se borra
+        let submit = {
+            let entry = widgets.passphrase_entry.clone();
+            let confirm = widgets.passphrase_confirm.clone();
+            let s = sender.clone();
+            move || {
+                s.input(KlipBoredMsg::SubmitPassphrase(
+                    entry.text().to_string(),
+                    confirm.text().to_string(),
+                ));
+                entry.set_text("");
+                confirm.set_text("");
+                entry.grab_focus();
+            }
+        };
+        let submit_activate = submit.clone();
+        let confirm = widgets.passphrase_confirm.clone();
+        widgets.passphrase_entry.connect_activate(move |_| {
+            if confirm.is_visible() {
+                confirm.grab_focus();
+            } else {
+                submit_activate();
+            }
+        });
+        let submit_confirm = submit.clone();
+        widgets
+            .passphrase_confirm
+            .connect_activate(move |_| submit_confirm());
+        widgets.unlock_button.connect_clicked(move |_| submit());
+
+        // Al abrir la ventana se empieza una búsqueda nueva; lo que se teclee
+        // en cualquier parte va a parar al buscador
+        let search_entry = widgets.search_entry.clone();
+        search_entry.set_key_capture_widget(Some(&root_ref));
+        root_ref.connect_visible_notify(move |win| {
+            if win.is_visible() {
+                search_entry.set_text("");
+                search_entry.grab_focus();
+            }
+        });
+
         // Escape solo cierra si ya se completó el wizard
         let esc_controller = gtk::EventControllerKey::new();
         let root_for_esc = root_ref.clone();
@@ -663,6 +2141,105 @@ impl SimpleComponent for KlipBoredModel {
         });
         root_ref.add_controller(esc_controller);
 
+        // Navegación por teclado en la lista. Va en fase de captura para ver
+        // las flechas y Enter antes que el buscador; j/k y Supr solo cuentan
+        // fuera de él, donde son texto.
+        let keys = gtk::EventControllerKey::new();
+        keys.set_propagation_phase(gtk::PropagationPhase::Capture);
+        let list_keys = list_box.clone();
+        let search_keys = widgets.search_entry.clone();
+        let root_keys = root_ref.clone();
+        let s_keys = sender.clone();
+        keys.connect_key_pressed(move |_, key, _, state| {
+            if !search_keys.is_mapped() {
+                return glib::Propagation::Proceed;
+            }
+            let in_search =
+                GtkWindowExt::focus(&root_keys).is_some_and(|w| w.is_ancestor(&search_keys));
+            let ctrl = state.contains(gdk::ModifierType::CONTROL_MASK);
+            let shift = state.contains(gdk::ModifierType::SHIFT_MASK);
+            let rows = visible_rows(&list_keys);
+            let selected = list_keys
+                .selected_row()
+                .and_then(|row| rows.iter().position(|r| *r == row));
+
+            let step = match key {
+                gdk::Key::Down => Some(1),
+                gdk::Key::Up => Some(-1),
+                gdk::Key::j if !in_search && !ctrl => Some(1),
+                gdk::Key::k if !in_search && !ctrl => Some(-1),
+                _ => None,
+            };
+            if let Some(step) = step {
+                let next = match selected {
+                    Some(i) => i
+                        .saturating_add_signed(step)
+                        .min(rows.len().saturating_sub(1)),
+                    None => 0,
+                };
+                if let Some(row) = rows.get(next) {
+                    list_keys.select_row(Some(row));
+                    row.grab_focus();
+                }
+                return glib::Propagation::Stop;
+            }
+
+            match key {
+                gdk::Key::Return | gdk::Key::KP_Enter => {
+                    // Sin nada seleccionado vale el primer resultado
+                    if let Some(row) = rows.get(selected.unwrap_or(0)) {
+                        s_keys.input(KlipBoredMsg::CopyAt(row.index() as usize, !shift));
+                    }
+                    glib::Propagation::Stop
+                }
+                gdk::Key::Delete if !in_search => {
+                    if let Some(i) = selected {
+                        // La selección pasa a la vecina antes de borrar
+                        if let Some(next) = rows
+                            .get(i + 1)
+                            .or(i.checked_sub(1).and_then(|p| rows.get(p)))
+                        {
+                            list_keys.select_row(Some(next));
+                            next.grab_focus();
+                        }
+                        s_keys.input(KlipBoredMsg::DeleteAt(rows[i].index() as usize));
+                    }
+                    glib::Propagation::Stop
+                }
+                gdk::Key::f if ctrl => {
+                    search_keys.grab_focus();
+                    glib::Propagation::Stop
+                }
+                _ if ctrl => {
+                    // Ctrl+1…9: la enésima entrada visible
+                    let nth = key
+                        .to_unicode()
+                        .and_then(|c| c.to_digit(10))
+                        .filter(|&n| n > 0);
+                    match nth.and_then(|n| rows.get(n as usize - 1)) {
+                        Some(row) => {
+                            s_keys.input(KlipBoredMsg::CopyAt(row.index() as usize, true));
+                            glib::Propagation::Stop
+                        }
+                        None => glib::Propagation::Proceed,
+                    }
+                }
+                _ => glib::Propagation::Proceed,
+            }
+        });
+        root_ref.add_controller(keys);
+
+        // Un clic en cualquier parte de la fila la recupera
+        let s_rows = sender.clone();
+        list_box.connect_row_activated(move |_, row| {
+            s_rows.input(KlipBoredMsg::CopyAt(row.index() as usize, true));
+        });
+
+        // Ventana de ayuda con los atajos (Ctrl+? o F1)
+        let builder = gtk::Builder::from_string(SHORTCUTS_UI);
+        let help_overlay: gtk::ShortcutsWindow = builder.object("help_overlay").unwrap();
+        root_ref.set_help_overlay(Some(&help_overlay));
+
         // Bloquear cierre de ventana durante el wizard
         let setup_done_close = setup_done.clone();
         root_ref.connect_close_request(move |_| {
@@ -684,45 +2261,114 @@ impl SimpleComponent for KlipBoredModel {
     }
 
     fn update(&mut self, msg: Self::Input, sender: ComponentSender<Self>) {
+        // Mientras se vuelve a cifrar, lo que cambiaría el historial espera a
+        // `Rekeyed`; si es para borrarlo todo, el cifrado se deja a medias
+        if self.store.is_rekeying() {
+            if matches!(msg, KlipBoredMsg::WipeHistory | KlipBoredMsg::Reset(true)) {
+                self.store.cancel_rekey();
+            }
+            if matches!(
+                msg,
+                KlipBoredMsg::NewItem(_)
+                    | KlipBoredMsg::DeleteItem(_)
+                    | KlipBoredMsg::DeleteAt(_)
+                    | KlipBoredMsg::ClearHistory
+                    | KlipBoredMsg::PruneExpired
+                    | KlipBoredMsg::SessionEnded
+                    | KlipBoredMsg::WipeHistory
+                    | KlipBoredMsg::Reset(true)
+                    | KlipBoredMsg::ImportFrom(_)
+                    | KlipBoredMsg::DBus(..)
+            ) {
+                self.deferred.push(msg);
+                return;
+            }
+        }
         match msg {
             KlipBoredMsg::WizardAccept => {
-                save_keybinding("<Super>v");
-                *self.setup_done.borrow_mut() = true;
-                self.current_page = "clipboard".to_string();
-                if let Ok(p) = std::env::current_exe() {
-                    if let Some(s) = p.to_str() {
-                        setup_gsettings_binding(s, "<Super>v");
-                    }
-                }
-                let app = gtk::Application::default();
-                if let Some(win) = app.active_window() {
-                    win.set_visible(false);
-                }
+                self.request_binding(DEFAULT_BINDING.to_string(), true, &sender);
             }
             KlipBoredMsg::WizardShowCustom => {
                 self.current_page = "wizard_custom".to_string();
             }
             KlipBoredMsg::WizardApplyBinding(binding) => {
-                save_keybinding(&binding);
-                self.current_binding = binding.clone();
-                *self.setup_done.borrow_mut() = true;
-
-                if let Ok(p) = std::env::current_exe() {
-                    if let Some(s) = p.to_str() {
-                        setup_gsettings_binding(s, &binding);
+                let from_wizard = self.current_page == "wizard_custom";
+                self.request_binding(binding, from_wizard, &sender);
+            }
+            KlipBoredMsg::OverrideConflict => {
+                if let Some(pending) = self.pending_binding.take() {
+                    self.apply_binding(
+                        &pending.binding,
+                        &pending.conflicts,
+                        pending.from_wizard,
+                        &sender,
+                    );
+                }
+            }
+            KlipBoredMsg::ReassignRecorded(res) => {
+                let Some(pending) = &self.pending_binding else {
+                    return;
+                };
+                let replacement = match res {
+                    Ok(accel) => accel,
+                    Err(e) => {
+                        self.conflict_error = format!("No se puede usar: {}", e);
+                        return;
                     }
+                };
+                if let Some(other) = keybinding::conflicts(&replacement).first() {
+                    self.conflict_error = format!(
+                        "{} también lo usa {}",
+                        shortcut::label(&replacement),
+                        other.name
+                    );
+                    return;
                 }
-
-                if self.current_page == "wizard_custom" {
-                    self.current_page = "clipboard".to_string();
-                    let app = gtk::Application::default();
-                    if let Some(win) = app.active_window() {
-                        win.set_visible(false);
+                if let Err(e) = keybinding::reassign(
+                    &pending.conflicts[0],
+                    &pending.binding,
+                    &replacement,
+                    &mut self.settings.overridden_bindings,
+                ) {
+                    self.conflict_error = format!("No se pudo cambiar: {}", e);
+                    return;
+                }
+                if let Some(pending) = self.pending_binding.take() {
+                    self.apply_binding(&pending.binding, &[], pending.from_wizard, &sender);
+                }
+            }
+            KlipBoredMsg::PortalShortcuts(available) => {
+                if !available {
+                    return;
+                }
+                self.portal_shortcuts = true;
+                self._shortcut_activated =
+                    global_shortcut::connect_activated(|| relm4::main_application().activate());
+                if *self.setup_done.borrow() {
+                    self.register_binding(&[], &sender);
+                }
+            }
+            KlipBoredMsg::ShortcutBound(res) => match res {
+                Ok((session, trigger)) => {
+                    self.shortcut_session = Some(session);
+                    self.binding_note = format!("en el escritorio: {}", trigger);
+                    // Con el portal confirmado, el atajo de GNOME sobra
+                    match keybinding::unregister(&mut self.settings.overridden_bindings) {
+                        Ok(true) => self.settings.save(),
+                        Ok(false) => {}
+                        Err(e) => {
+                            eprintln!("klipBored: no se pudo quitar el atajo de GNOME: {}", e)
+                        }
                     }
-                } else {
-                    // If we were in settings, stay in settings or go back
-                    self.current_page = "settings".to_string();
                 }
+                Err(e) => {
+                    eprintln!("klipBored: el portal no registró el atajo: {}", e);
+                    self.binding_note = format!("el escritorio no lo registró: {}", e);
+                }
+            },
+            KlipBoredMsg::ChooseAnotherBinding => {
+                self.pending_binding = None;
+                self.current_page = "wizard_custom".to_string();
             }
             KlipBoredMsg::OpenSettings => {
                 self.current_page = "settings".to_string();
@@ -732,227 +2378,1683 @@ impl SimpleComponent for KlipBoredModel {
                 set_autostart(enabled);
                 self.autostart_enabled = enabled;
             }
-            KlipBoredMsg::BackToClipboard => {
-                if self.current_page == "wizard_custom" && !*self.setup_done.borrow() {
-                    self.current_page = "wizard".to_string();
-                } else {
-                    self.current_page = "clipboard".to_string();
-                }
+            KlipBoredMsg::ToggleCapturePrimary(enabled) => {
+                self.settings.capture.primary = enabled;
+                self.settings.save();
             }
-
-            KlipBoredMsg::UpdateManualBinding(text) => {
-                self.manual_binding = text.clone();
-                // Validación básica de formato de atajo de GTK
-                if text.is_empty() {
-                    self.binding_status = "ok".to_string();
-                } else if text.contains('<') && text.contains('>') && text.len() > 3 {
-                    self.binding_status = "ok".to_string();
-                } else {
-                    self.binding_status = "error".to_string();
+            KlipBoredMsg::ToggleAutoPaste(enabled) => {
+                self.settings.window.auto_paste = enabled;
+                self.settings.save();
+            }
+            KlipBoredMsg::SetCopyTarget(target) => {
+                self.settings.window.copy_target = target;
+                self.settings.save();
+            }
+            KlipBoredMsg::SetTextMaxAge(days) => {
+                self.settings.history.text_max_age_days = days;
+                self.settings.save();
+                self.prune_expired();
+            }
+            KlipBoredMsg::SetImageMaxAge(days) => {
+                self.settings.history.image_max_age_days = days;
+                self.settings.save();
+                self.prune_expired();
+            }
+            KlipBoredMsg::SetFilesMaxAge(days) => {
+                self.settings.history.files_max_age_days = days;
+                self.settings.save();
+                self.prune_expired();
+            }
+            KlipBoredMsg::SetClearSchedule(schedule) => {
+                self.settings.history.clear_schedule = schedule;
+                self.settings.save();
+                self.prune_expired();
+            }
+            KlipBoredMsg::SetTheme(theme) => {
+                self.settings.window.theme = theme;
+                self.settings.save();
+                apply_theme(theme);
+            }
+            KlipBoredMsg::ToggleHideOnFocusLoss(enabled) => {
+                self.settings.window.hide_on_focus_loss = enabled;
+                self.hide_on_focus_loss.set(enabled);
+                self.settings.save();
+            }
+            KlipBoredMsg::SetExportRange(range) => self.export_range = range,
+            KlipBoredMsg::ChooseExport => {
+                let today = glib::DateTime::now_local()
+                    .and_then(|now| now.format("%Y-%m-%d"))
+                    .unwrap_or_default();
+                let dialog = gtk::FileDialog::builder()
+                    .title("Exportar historial")
+                    .initial_name(format!("klipBored-{}.tar", today))
+                    .build();
+                let s = sender.clone();
+                let parent = gtk::Application::default().active_window();
+                dialog.save(parent.as_ref(), gio::Cancellable::NONE, move |res| {
+                    if let Some(path) = res.ok().and_then(|file| file.path()) {
+                        s.input(KlipBoredMsg::ExportTo(path));
+                    }
+                });
+            }
+            KlipBoredMsg::ChooseImport => {
+                let filter = gtk::FileFilter::new();
+                filter.set_name(Some("Archivos de klipBored"));
+                filter.add_suffix("tar");
+                let dialog = gtk::FileDialog::builder()
+                    .title("Importar historial")
+                    .default_filter(&filter)
+                    .build();
+                let s = sender.clone();
+                let parent = gtk::Application::default().active_window();
+                dialog.open(parent.as_ref(), gio::Cancellable::NONE, move |res| {
+                    if let Some(path) = res.ok().and_then(|file| file.path()) {
+                        s.input(KlipBoredMsg::ImportFrom(path));
+                    }
+                });
+            }
+            KlipBoredMsg::ExportTo(path) => {
+                let range = self.export_range;
+                let items: Vec<&HistoryItem> = self
+                    .clipboard_entries
+                    .iter()
+                    .map(|entry| &entry.item)
+                    .filter(|item| range.includes(item))
+                    .collect();
+                self.archive_status = match export_items(&path, &items) {
+                    Ok(msg) | Err(msg) => msg,
+                };
+                // El diálogo le quitó el foco y la ventana se ocultó
+                if let Some(win) = gtk::Application::default().windows().first() {
+                    win.present();
+                }
+            }
+            KlipBoredMsg::ImportFrom(path) => {
+                self.archive_status = match self.import_history(&path) {
+                    Ok(msg) | Err(msg) => msg,
+                };
+                if let Some(win) = gtk::Application::default().windows().first() {
+                    win.present();
+                }
+            }
+            KlipBoredMsg::ChooseSettingsExport => {
+                let dialog = gtk::FileDialog::builder()
+                    .title("Exportar ajustes")
+                    .initial_name("klipBored.toml")
+                    .build();
+                let s = sender.clone();
+                let parent = gtk::Application::default().active_window();
+                dialog.save(parent.as_ref(), gio::Cancellable::NONE, move |res| {
+                    if let Some(path) = res.ok().and_then(|file| file.path()) {
+                        s.input(KlipBoredMsg::ExportSettingsTo(path));
+                    }
+                });
+            }
+            KlipBoredMsg::ChooseSettingsImport => {
+                let filter = gtk::FileFilter::new();
+                filter.set_name(Some("Ajustes de klipBored"));
+                filter.add_suffix("toml");
+                let dialog = gtk::FileDialog::builder()
+                    .title("Importar ajustes")
+                    .default_filter(&filter)
+                    .build();
+                let s = sender.clone();
+                let parent = gtk::Application::default().active_window();
+                dialog.open(parent.as_ref(), gio::Cancellable::NONE, move |res| {
+                    if let Some(path) = res.ok().and_then(|file| file.path()) {
+                        s.input(KlipBoredMsg::ImportSettingsFrom(path));
+                    }
+                });
+            }
+            KlipBoredMsg::ExportSettingsTo(path) => {
+                self.config_error = match self.settings.save_to(&path) {
+                    Ok(()) => String::new(),
+                    Err(e) => format!("No se pudo exportar a {}: {}", path.display(), e),
+                };
+                if let Some(win) = gtk::Application::default().windows().first() {
+                    win.present();
                 }
             }
+            KlipBoredMsg::ImportSettingsFrom(path) => {
+                self.config_error = match self.import_settings(&path, &sender) {
+                    Ok(warnings) => warnings.join("\n"),
+                    Err(msg) => msg,
+                };
+                if let Some(win) = gtk::Application::default().windows().first() {
+                    win.present();
+                }
+            }
+            KlipBoredMsg::Reset(purge) => match self.reset(purge, &sender) {
+                Ok(steps) => self.reset_report = steps.join("\n"),
+                Err(msg) => self.config_error = msg,
+            },
+            KlipBoredMsg::SetSensitiveMode(mode) => {
+                self.settings.privacy.sensitive = mode;
+                self.settings.save();
+            }
+            KlipBoredMsg::SetMaxEntries(n) => {
+                self.settings.history.max_entries = n;
+                self.settings.save();
+                self.enforce_limits();
+                self.save_history();
+            }
+            KlipBoredMsg::SetMaxTotalMb(mb) => {
+                let history = &mut self.settings.history;
+                history.max_total_mb = mb;
+                history.max_image_mb = history.max_image_mb.min(mb);
+                self.settings.save();
+                self.enforce_limits();
+                self.save_history();
+            }
+            KlipBoredMsg::SetMaxImageMb(mb) => {
+                self.settings.history.max_image_mb = mb.min(self.settings.history.max_total_mb);
+                self.settings.save();
+                self.enforce_limits();
+                self.save_history();
+            }
+            KlipBoredMsg::BackToClipboard => {
+                if self.current_page == "unlock" {
+                    self.unlock_error.clear();
+                    self.current_page = "settings".to_string();
+                } else if self.current_page == "binding_conflict" {
+                    self.pending_binding = None;
+                    self.current_page = "wizard_custom".to_string();
+                } else if self.current_page == "wizard_custom" && !*self.setup_done.borrow() {
+                    self.current_page = "wizard".to_string();
+                } else {
+                    self.current_page = "clipboard".to_string();
+                }
+            }
+
+            KlipBoredMsg::BindingRecorded(res) => match res {
+                Ok(binding) => {
+                    self.recorded_binding = binding;
+                    self.binding_error.clear();
+                }
+                Err(e) => {
+                    self.recorded_binding.clear();
+                    self.binding_error = format!("No se puede usar: {}", e);
+                }
+            },
 
             KlipBoredMsg::ApplyManualBinding => {
-                let binding = self.manual_binding.clone();
+                let binding = std::mem::take(&mut self.recorded_binding);
                 sender.input(KlipBoredMsg::WizardApplyBinding(binding));
             }
 
-            KlipBoredMsg::NewItem(content) => {
+            KlipBoredMsg::ClipboardChanged(Selection::Clipboard) => {
+                self.capture(Selection::Clipboard, &sender);
+            }
+            KlipBoredMsg::ClipboardChanged(Selection::Primary) => {
+                if !self.settings.capture.primary {
+                    return;
+                }
+                self.primary_serial += 1;
+                let serial = self.primary_serial;
+                let s = sender.clone();
+                glib::timeout_add_local_once(PRIMARY_DEBOUNCE, move || {
+                    s.input(KlipBoredMsg::PrimarySettled(serial));
+                });
+            }
+            KlipBoredMsg::PrimarySettled(serial) => {
+                // Solo cuenta el último aviso; los anteriores eran la selección a medias
+                if serial == self.primary_serial {
+                    self.capture(Selection::Primary, &sender);
+                }
+            }
+            KlipBoredMsg::NewItem(captured) => self.add_captured(captured),
+            KlipBoredMsg::DeleteItem(index) => self.delete_at(index.current_index()),
+            KlipBoredMsg::TogglePin(index) => {
+                let pos = index.current_index();
+                if let Some(entry) = self.clipboard_entries.get(pos) {
+                    self.set_pinned(pos, !entry.item.pinned);
+                }
+            }
+            KlipBoredMsg::TogglePasteKeys(index) => {
+                let mut guard = self.clipboard_entries.guard();
+                if let Some(entry) = guard.get_mut(index.current_index()) {
+                    entry.item.paste_keys = match entry.item.paste_keys {
+                        PasteKeys::CtrlV => PasteKeys::CtrlShiftV,
+                        PasteKeys::CtrlShiftV => PasteKeys::CtrlV,
+                    };
+                }
+                drop(guard);
+                self.save_history();
+            }
+            KlipBoredMsg::SetExpiry(index, expiry) => {
                 let mut guard = self.clipboard_entries.guard();
-                guard.push_front(content);
-                if guard.len() > 50 {
-                    guard.pop_back();
+                if let Some(entry) = guard.get_mut(index.current_index()) {
+                    entry.item.expiry = expiry;
+                }
+                drop(guard);
+                self.prune_expired();
+                self.save_history();
+            }
+            KlipBoredMsg::ClearHistory => {
+                self.remove_where(|item| !item.pinned);
+                self.save_history();
+            }
+            KlipBoredMsg::PruneExpired => self.prune_expired(),
+            KlipBoredMsg::SessionEnded => self.end_session(),
+            KlipBoredMsg::KeyReady(cipher) => {
+                self.uses_keyring = true;
+                self.unlock_with(cipher, &sender);
+            }
+            KlipBoredMsg::NeedPassphrase => {
+                self.uses_keyring = false;
+                self.unlock_mode = if vault::uses_passphrase() {
+                    UnlockMode::Unlock
+                } else {
+                    UnlockMode::Create
+                };
+                self.current_page = "unlock".to_string();
+                // Hasta tener la clave no se guarda nada; mejor que se vea
+                if let Some(win) = gtk::Application::default().windows().first() {
+                    win.present();
+                }
+            }
+            KlipBoredMsg::SubmitPassphrase(passphrase, confirmation) => {
+                if passphrase.is_empty() {
+                    return;
+                }
+                if self.unlock_mode == UnlockMode::Unlock {
+                    match vault::from_passphrase(&passphrase) {
+                        Ok(cipher) => self.unlock_with(cipher, &sender),
+                        Err(e) => self.unlock_error = format!("No se pudo derivar la clave: {}", e),
+                    }
+                    return;
                 }
+                if passphrase != confirmation {
+                    self.unlock_error = "Las contraseñas no coinciden.".to_string();
+                    return;
+                }
+                let (cipher, key) = match vault::new_passphrase(&passphrase) {
+                    Ok(new) => new,
+                    Err(e) => {
+                        self.unlock_error = format!("No se pudo derivar la clave: {}", e);
+                        return;
+                    }
+                };
+                self.unlock_error.clear();
+                if self.unlock_mode == UnlockMode::Rekey {
+                    self.rekey(cipher, key, &sender);
+                    self.current_page = "settings".to_string();
+                } else if let Err(e) = key.save() {
+                    self.unlock_error = format!("No se pudo guardar la contraseña: {}", e);
+                } else {
+                    self.unlock_with(cipher, &sender);
+                }
+            }
+            KlipBoredMsg::RekeyHistory => {
+                if !self.uses_keyring {
+                    self.unlock_mode = UnlockMode::Rekey;
+                    self.unlock_error.clear();
+                    self.current_page = "unlock".to_string();
+                    return;
+                }
+                let (cipher, key) = vault::new_keyring_key();
+                self.rekey(cipher, key, &sender);
+            }
+            KlipBoredMsg::Rekeyed(res) => {
+                self.store.finish_rekey(res.is_ok());
+                if let Err(e) = res {
+                    eprintln!("klipBored: no se pudo cambiar la clave: {}", e);
+                }
+                // Lo que haya cambiado mientras tanto, con la clave que quede
+                self.save_history();
+                // Lo que llegó con el historial cerrado, en el orden en que llegó
+                for msg in std::mem::take(&mut self.deferred) {
+                    SimpleComponent::update(self, msg, sender.clone());
+                }
+                for (command, request) in std::mem::take(&mut self.pending_commands) {
+                    self.handle_command(command, request, &sender);
+                }
+            }
+            KlipBoredMsg::WipeHistory => {
+                self.remove_where(|_| true);
+                self.store.wipe();
+                self.unlock_error.clear();
+                find_key(sender.clone(), true);
             }
-            KlipBoredMsg::DeleteItem(index) => {
-                self.clipboard_entries.guard().remove(index.current_index());
+            KlipBoredMsg::Search(query) => {
+                self.query = query;
+                self.apply_filter();
+            }
+            KlipBoredMsg::SearchActivate => {
+                let pos = self
+                    .clipboard_entries
+                    .iter()
+                    .position(|entry| entry.matches);
+                if let Some(pos) = pos {
+                    self.copy_entry(pos, true);
+                }
             }
             KlipBoredMsg::RequestCopy(index) => {
-                if let Some(entry) = self.clipboard_entries.get(index.current_index()) {
-                    let content = entry.content.clone();
-
-                    {
-                        let mut state = self.tracker.borrow_mut();
-                        state.last_own_copy = Instant::now();
-                        match &content {
-                            ClipboardContent::Text { full, .. } => state.last_text = full.clone(),
-                            ClipboardContent::Image { raw, .. } => {
-                                state.last_img_hash = calculate_hash(&raw.data)
-                            }
+                self.copy_entry(index.current_index(), true);
+            }
+            KlipBoredMsg::CopyTo(index, target) => {
+                self.copy_entry_to(index.current_index(), true, target);
+            }
+            KlipBoredMsg::CopyAt(pos, hide) => self.copy_entry(pos, hide),
+            KlipBoredMsg::DeleteAt(pos) => self.delete_at(pos),
+            KlipBoredMsg::DBus(call, request) => self.handle_dbus(call, request),
+            KlipBoredMsg::CommandLine(command, request) => {
+                self.handle_command(command, request, &sender)
+            }
+            KlipBoredMsg::CopyInput(opts, data, request) => {
+                let res = self.copy_input(opts, data);
+                reply(&self.commands, request, res);
+            }
+        }
+    }
+}
+
+impl KlipBoredModel {
+    /// Recupera la entrada en la posición `pos` y, si `hide`, oculta la
+    /// ventana y, con el pegado automático, la pega en la que tenía el foco.
+    fn copy_entry(&mut self, pos: usize, hide: bool) {
+        self.copy_entry_to(pos, hide, self.settings.window.copy_target);
+    }
+
+    /// Como `copy_entry`, pero en `target` y no donde digan los ajustes.
+    fn copy_entry_to(&mut self, pos: usize, hide: bool, target: CopyTarget) {
+        let Some(entry) = self.clipboard_entries.get(pos) else {
+            return;
+        };
+        let content = entry.item.content.clone();
+        let mut formats = entry.item.formats.clone();
+        let paste_keys = entry.item.paste_keys;
+        if entry.item.sensitive {
+            // Que otros gestores del portapapeles tampoco lo guarden
+            formats.push(MimeData {
+                mime: "x-kde-passwordManagerHint".to_string(),
+                data: b"secret".to_vec(),
+            });
+        }
+
+        if hide {
+            let app = gtk::Application::default();
+            if let Some(win) = app.active_window() {
+                win.set_visible(false);
+            }
+            // Ctrl+V pega el portapapeles, no la selección primaria
+            if self.settings.window.auto_paste && target != CopyTarget::Primary {
+                paste::send(paste_keys, PASTE_DELAY);
+            }
+        }
+        self.set_selection(content, formats, target);
+    }
+
+    /// Pone `content` en el portapapeles, en la selección primaria o en
+    /// ambos, sin que vuelva a entrar en el historial como copia nueva: se
+    /// apunta como lo último visto, así que solo cuenta si cambia.
+    fn set_selection(
+        &mut self,
+        content: ClipboardContent,
+        formats: Vec<MimeData>,
+        target: CopyTarget,
+    ) {
+        {
+            let mut tracker = lock(&self.tracker);
+            if target != CopyTarget::Primary {
+                tracker.remember(&content);
+            }
+            if let ClipboardContent::Text { full, .. } = &content {
+                if target != CopyTarget::Clipboard {
+                    tracker.last_primary_text = full.clone();
+                }
+            }
+        }
+
+        if !formats.is_empty() || matches!(content, ClipboardContent::Files { .. }) {
+            // Con formatos ricos se ofrecen todos a la vez, más el
+            // texto plano o PNG para quien no entienda los demás
+            mime::write(&with_plain_fallback(&content, formats), target);
+            return;
+        }
+
+        let kinds: &[LinuxClipboardKind] = match target {
+            CopyTarget::Clipboard => &[LinuxClipboardKind::Clipboard],
+            CopyTarget::Primary => &[LinuxClipboardKind::Primary],
+            CopyTarget::Both => &[LinuxClipboardKind::Clipboard, LinuxClipboardKind::Primary],
+        };
+        std::thread::spawn(move || {
+            if let Ok(mut cb) = Clipboard::new() {
+                for &kind in kinds {
+                    match &content {
+                        ClipboardContent::Text { full, .. } => {
+                            let _ = cb.set().clipboard(kind).text(full.clone());
+                        }
+                        ClipboardContent::Image { raw, .. } => {
+                            let data = ImageData {
+                                width: raw.width,
+                                height: raw.height,
+                                bytes: Cow::Borrowed(&raw.data),
+                            };
+                            let _ = cb.set().clipboard(kind).image(data);
                         }
+                        // Siempre van por `mime::write`
+                        ClipboardContent::Files { .. } => {}
                     }
+                }
+                std::thread::sleep(Duration::from_millis(600));
+            }
+        });
+    }
 
-                    let app = gtk::Application::default();
-                    if let Some(win) = app.active_window() {
-                        win.set_visible(false);
-                    }
+    /// Guarda en el historial lo leído del portapapeles. Si ya estaba, la
+    /// entrada sube arriba en vez de repetirse.
+    fn add_captured(&mut self, captured: Captured) {
+        let secret = match &captured.content {
+            ClipboardContent::Text { full, .. } => sensitive::detect(full),
+            _ => None,
+        };
+        if secret.is_some() && self.settings.privacy.sensitive == SensitiveMode::Skip {
+            return;
+        }
+        let hash = content_hash(&captured.content);
+        let existing = self
+            .clipboard_entries
+            .iter()
+            .position(|entry| entry.item.hash == hash);
+        if let Some(pos) = existing {
+            // Ya estaba en el historial: se sube arriba en vez de duplicarla
+            let pinned = self.pinned_count();
+            let mut guard = self.clipboard_entries.guard();
+            if let Some(entry) = guard.remove(pos) {
+                let mut item = entry.item;
+                item.times_copied += 1;
+                item.created = history::now_unix();
+                let top = if item.pinned { 0 } else { pinned };
+                guard.insert(top, item);
+            }
+            drop(guard);
+            if !self.query.is_empty() {
+                self.apply_filter();
+            }
+            self.save_history();
+            return;
+        }
 
-                    std::thread::spawn(move || {
-                        if let Ok(mut cb) = Clipboard::new() {
-                            match content {
-                                ClipboardContent::Text { full, .. } => {
-                                    let _ = cb.set_text(full);
-                                }
-                                ClipboardContent::Image { raw, .. } => {
-                                    let data = ImageData {
-                                        width: raw.width,
-                                        height: raw.height,
-                                        bytes: Cow::Borrowed(&raw.data),
-                                    };
-                                    let _ = cb.set_image(data);
-                                }
-                            }
-                            std::thread::sleep(Duration::from_millis(600));
-                        }
-                    });
+        let source = captured.source;
+        let item = HistoryItem {
+            id: self.next_id,
+            created: history::now_unix(),
+            source,
+            content: captured.content,
+            formats: captured.formats,
+            times_copied: 1,
+            pinned: false,
+            paste_keys: PasteKeys::default(),
+            expiry: Expiry::default(),
+            sensitive: secret.is_some(),
+            hash,
+        };
+        self.next_id += 1;
+        self.store.add(&item);
+
+        // Las nuevas van justo debajo de las fijadas
+        let top = self.pinned_count();
+        let mut guard = self.clipboard_entries.guard();
+        let replaces_front = guard
+            .get(top)
+            .is_some_and(|front| is_partial_selection(&front.item, &item));
+        if replaces_front {
+            if let Some(old) = guard.remove(top) {
+                self.store.remove(&old.item);
+                self.bus.item_removed(old.item.id);
+            }
+        }
+        let id = item.id;
+        guard.insert(top, item);
+        drop(guard);
+        self.bus.item_added(id);
+        self.enforce_limits();
+        if !self.query.is_empty() {
+            self.apply_filter();
+        }
+        self.save_history();
+    }
+
+    fn capture(&mut self, selection: Selection, sender: &ComponentSender<Self>) {
+        // Solo se captura una vez completado el wizard y abierto el historial;
+        // si se está volviendo a cifrar, la entrada espera a que termine
+        let open = self.store.is_unlocked() || self.store.is_rekeying();
+        if !*self.setup_done.borrow() || !open || self.paused {
+            return;
+        }
+        let tracker = self.tracker.clone();
+        let sender = sender.clone();
+        std::thread::spawn(move || {
+            if let Some(captured) = ClipboardTracker::capture(&tracker, selection) {
+                sender.input(KlipBoredMsg::NewItem(captured));
+            }
+        });
+    }
+
+    /// Descarta entradas, empezando por las más antiguas, hasta cumplir los
+    /// límites de los ajustes. Si solo sobran imágenes, solo se descartan
+    /// imágenes. Las fijadas y la entrada más reciente se conservan siempre.
+    fn enforce_limits(&mut self) {
+        const MB: u64 = 1024 * 1024;
+        let max_entries = self.settings.history.max_entries;
+        let max_bytes = self.settings.history.max_total_mb * MB;
+        let max_image_bytes = self.settings.history.max_image_mb * MB;
+
+        let newest = self.pinned_count();
+        let mut guard = self.clipboard_entries.guard();
+        let mut usage = Usage::of(guard.iter().map(|entry| &entry.item));
+        let mut i = guard.len();
+        while i > newest + 1 {
+            i -= 1;
+            let over_total = usage.entries > max_entries || usage.bytes > max_bytes;
+            let over_images = usage.image_bytes > max_image_bytes;
+            if !over_total && !over_images {
+                break;
+            }
+            let Some(entry) = guard.get(i) else {
+                continue;
+            };
+            if entry.item.pinned || (!over_total && !entry.item.is_image()) {
+                continue;
+            }
+            if let Some(old) = guard.remove(i) {
+                usage.sub(&old.item);
+                self.store.remove(&old.item);
+                self.bus.item_removed(old.item.id);
+            }
+        }
+    }
+
+    /// Vuelve a cifrar el historial con `cipher`. Mientras dura, lo que
+    /// cambiaría el historial espera en `deferred`.
+    fn rekey(&mut self, cipher: vault::Cipher, key: vault::NewKey, sender: &ComponentSender<Self>) {
+        let s = sender.clone();
+        self.store.rekey(
+            cipher,
+            key,
+            self.clipboard_entries.iter().map(|entry| &entry.item),
+            move |res| s.input(KlipBoredMsg::Rekeyed(res)),
+        );
+    }
+
+    /// Abre el historial con `cipher` y lo carga, o pide la contraseña si
+    /// no es la clave con la que se cifró.
+    fn unlock_with(&mut self, cipher: vault::Cipher, sender: &ComponentSender<Self>) {
+        if let Err(e) = self.store.unlock(cipher) {
+            eprintln!("klipBored: {}", e);
+            self.unlock_error = if self.uses_keyring {
+                "La clave del llavero no abre este historial.".to_string()
+            } else {
+                "Contraseña incorrecta.".to_string()
+            };
+            self.unlock_mode = UnlockMode::Unlock;
+            self.current_page = "unlock".to_string();
+            return;
+        }
+        self.unlock_error.clear();
+        if self.current_page == "unlock" {
+            self.current_page = if *self.setup_done.borrow() {
+                "clipboard".to_string()
+            } else {
+                "wizard".to_string()
+            };
+        }
+        self.load_history();
+        // Lo que hubiera copiado antes de arrancar no genera aviso
+        sender.input(KlipBoredMsg::ClipboardChanged(Selection::Clipboard));
+        for (command, request) in std::mem::take(&mut self.pending_commands) {
+            self.handle_command(command, request, sender);
+        }
+    }
+
+    fn load_history(&mut self) {
+        let history = self.store.load();
+
+        // Lo último guardado cuenta como "ya visto" para no duplicarlo al arrancar
+        if let Some(item) = history.first() {
+            lock(&self.tracker).remember(&item.content);
+        }
+        self.next_id = history.iter().map(|item| item.id + 1).max().unwrap_or(1);
+
+        let mut guard = self.clipboard_entries.guard();
+        guard.clear();
+        for item in history {
+            guard.push_back(item);
+        }
+        drop(guard);
+
+        self.prune_expired();
+        if std::mem::take(&mut self.new_session) {
+            self.end_session();
+        }
+    }
+
+    /// Borrado al cerrar sesión, si está activado.
+    fn end_session(&mut self) {
+        if self.settings.history.clear_schedule == ClearSchedule::Logout
+            && self.remove_where(|item| !item.pinned && item.expiry != Expiry::Never)
+        {
+            self.save_history();
+        }
+    }
+
+    fn delete_at(&mut self, pos: usize) {
+        let removed = self.clipboard_entries.guard().remove(pos);
+        if let Some(entry) = removed {
+            self.store.remove(&entry.item);
+            self.bus.item_removed(entry.item.id);
+            self.save_history();
+        }
+    }
+
+    fn set_pinned(&mut self, pos: usize, pinned: bool) {
+        let mut guard = self.clipboard_entries.guard();
+        if let Some(entry) = guard.remove(pos) {
+            let mut item = entry.item;
+            item.pinned = pinned;
+            // Al fijarla sube arriba del todo; al soltarla, encabeza las recientes
+            let pos = if item.pinned {
+                0
+            } else {
+                guard.iter().take_while(|e| e.item.pinned).count()
+            };
+            guard.insert(pos, item);
+        }
+        drop(guard);
+        if !self.query.is_empty() {
+            self.apply_filter();
+        }
+        self.save_history();
+    }
+
+    fn position_of(&self, id: u64) -> Option<usize> {
+        self.clipboard_entries
+            .iter()
+            .position(|entry| entry.item.id == id)
+    }
+
+    /// Responde a una llamada a la interfaz D-Bus.
+    fn handle_dbus(&mut self, call: dbus::Call, request: dbus::Request) {
+        let not_found = |id: u64| format!("no hay ninguna entrada con id {}", id);
+        let reply: Result<Option<glib::Variant>, String> = match call {
+            dbus::Call::List => {
+                let items: Vec<(u64, String, String, i64, bool)> = self
+                    .clipboard_entries
+                    .iter()
+                    .map(|entry| {
+                        let item = &entry.item;
+                        let (kind, preview) = summary(item);
+                        (
+                            item.id,
+                            kind.to_string(),
+                            preview,
+                            item.created,
+                            item.pinned,
+                        )
+                    })
+                    .collect();
+                Ok(Some((items,).to_variant()))
+            }
+            dbus::Call::Get(id, mime) => self
+                .position_of(id)
+                .and_then(|pos| self.clipboard_entries.get(pos))
+                .ok_or_else(|| not_found(id))
+                .and_then(|entry| {
+                    entry_data(&entry.item, &mime)
+                        .ok_or_else(|| format!("la entrada {} no tiene {}", id, mime))
+                })
+                .map(|data| Some((data,).to_variant())),
+            dbus::Call::Copy(id) => self
+                .position_of(id)
+                .ok_or_else(|| not_found(id))
+                .map(|pos| {
+                    self.copy_entry(pos, false);
+                    None
+                }),
+            dbus::Call::Delete(id) => {
+                self.position_of(id)
+                    .ok_or_else(|| not_found(id))
+                    .map(|pos| {
+                        self.delete_at(pos);
+                        None
+                    })
+            }
+            dbus::Call::Clear => {
+                self.remove_where(|item| !item.pinned);
+                self.save_history();
+                Ok(None)
+            }
+            dbus::Call::Pin(id, pinned) => {
+                self.position_of(id)
+                    .ok_or_else(|| not_found(id))
+                    .map(|pos| {
+                        self.set_pinned(pos, pinned);
+                        None
+                    })
+            }
+            dbus::Call::Pause(paused) => {
+                self.paused = paused;
+                Ok(None)
+            }
+        };
+        self.bus.reply(request, reply);
+    }
+
+    /// Ejecuta una orden de la línea de comandos y devuelve su salida y su
+    /// código de salida a quien la lanzó.
+    fn handle_command(
+        &mut self,
+        command: cli::Command,
+        request: cli::Request,
+        sender: &ComponentSender<Self>,
+    ) {
+        if command.needs_history() && !self.store.is_unlocked() {
+            self.pending_commands.push((command, request));
+            return;
+        }
+        let Some(cmdline) = self.commands.get(request) else {
+            return;
+        };
+        let window = gtk::Application::default().windows().first().cloned();
+        let res: Result<String, String> = match command {
+            cli::Command::Toggle => {
+                relm4::main_application().activate();
+                Ok(String::new())
+            }
+            cli::Command::Show => {
+                if let Some(win) = window {
+                    win.set_visible(true);
+                    win.present();
+                }
+                Ok(String::new())
+            }
+            cli::Command::Hide => {
+                if let Some(win) = window {
+                    win.set_visible(false);
                 }
+                Ok(String::new())
             }
+            cli::Command::List { json, limit } => Ok(self.list_entries(json, limit)),
+            cli::Command::Get(n) => self.entry_number(n).and_then(|pos| {
+                match &self.clipboard_entries[pos].item.content {
+                    ClipboardContent::Text { full, .. } => Ok(full.clone()),
+                    ClipboardContent::Files { uris, .. } => Ok(uris.join("\n") + "\n"),
+                    ClipboardContent::Image { .. } => Err(format!(
+                        "la entrada {} es una imagen; se puede sacar con el método Get de D-Bus",
+                        n
+                    )),
+                }
+            }),
+            cli::Command::Copy(n) => self.entry_number(n).map(|pos| {
+                self.copy_entry(pos, false);
+                String::new()
+            }),
+            cli::Command::CopyInput(opts) => match cmdline.stdin() {
+                Some(stdin) => {
+                    read_input(stdin, opts, request, self.commands.clone(), sender.clone());
+                    return;
+                }
+                None => Err("no se pudo leer la entrada estándar".to_string()),
+            },
+            // Se resuelve en el proceso que la pide, ver `paste_to_stdout`
+            cli::Command::Paste(_) => Err("paste no se reenvía".to_string()),
+            cli::Command::Delete(n) => self.entry_number(n).map(|pos| {
+                self.delete_at(pos);
+                String::new()
+            }),
+            cli::Command::Clear => {
+                self.remove_where(|item| !item.pinned);
+                self.save_history();
+                Ok(String::new())
+            }
+            cli::Command::Export {
+                path,
+                numbers,
+                since,
+                until,
+            } => {
+                let path = cmdline.create_file_for_arg(&path).path();
+                self.export_selection(&numbers, since, until)
+                    .and_then(|items| {
+                        let path = path.ok_or("ruta no válida")?;
+                        export_items(&path, &items)
+                    })
+                    .map(|msg| msg + "\n")
+            }
+            cli::Command::Import(path) => match cmdline.create_file_for_arg(&path).path() {
+                Some(path) => self.import_history(&path).map(|msg| msg + "\n"),
+                None => Err("ruta no válida".to_string()),
+            },
+            cli::Command::Pause(paused) => {
+                self.paused = paused;
+                Ok(String::new())
+            }
+            cli::Command::Reset { purge } => self
+                .reset(purge, sender)
+                .map(|steps| steps.join("\n") + "\n"),
+            cli::Command::Help => Ok(cli::USAGE.to_string()),
+        };
+        reply(&self.commands, request, res);
+    }
+
+    /// `klipBored copy` con la entrada estándar: la guarda en el historial,
+    /// como si se hubiera copiado, y la pone en el portapapeles.
+    fn copy_input(&mut self, opts: cli::CopyOptions, mut data: Vec<u8>) -> Result<String, String> {
+        if opts.trim_newline && data.last() == Some(&b'\n') {
+            data.pop();
+        }
+        if data.is_empty() {
+            return Err("no hay nada que copiar".to_string());
+        }
+        let mime = opts.mime.unwrap_or_else(|| guess_mime(&data));
+        let (source, target) = if opts.primary {
+            (Selection::Primary, CopyTarget::Primary)
+        } else {
+            (Selection::Clipboard, CopyTarget::Clipboard)
+        };
+        let captured = Captured::from_input(mime.clone(), data, source)
+            .ok_or_else(|| format!("no se puede copiar contenido de tipo {}", mime))?;
+        let content = captured.content.clone();
+        let mut formats = captured.formats.clone();
+        if let ClipboardContent::Text { full, .. } = &content {
+            if sensitive::detect(full).is_some() {
+                formats.push(MimeData {
+                    mime: "x-kde-passwordManagerHint".to_string(),
+                    data: b"secret".to_vec(),
+                });
+            }
+        }
+        if !self.paused {
+            self.add_captured(captured);
+        }
+        self.set_selection(content, formats, target);
+        Ok(String::new())
+    }
+
+    /// Posición de la entrada `n` de la línea de comandos (desde 1).
+    fn entry_number(&self, n: usize) -> Result<usize, String> {
+        if n <= self.clipboard_entries.len() {
+            Ok(n - 1)
+        } else {
+            Err(format!(
+                "no hay entrada {}; el historial tiene {}",
+                n,
+                self.clipboard_entries.len()
+            ))
+        }
+    }
+
+    /// Las entradas `numbers` (desde 1), o todas si no se da ninguna,
+    /// copiadas desde `since` y antes de `until`.
+    fn export_selection(
+        &self,
+        numbers: &[usize],
+        since: Option<i64>,
+        until: Option<i64>,
+    ) -> Result<Vec<&HistoryItem>, String> {
+        let items: Vec<&HistoryItem> = if numbers.is_empty() {
+            self.clipboard_entries
+                .iter()
+                .map(|entry| &entry.item)
+                .collect()
+        } else {
+            numbers
+                .iter()
+                .map(|&n| Ok(&self.clipboard_entries[self.entry_number(n)?].item))
+                .collect::<Result<_, String>>()?
+        };
+        Ok(items
+            .into_iter()
+            .filter(|item| since.is_none_or(|since| item.created >= since))
+            .filter(|item| until.is_none_or(|until| item.created < until))
+            .collect())
+    }
+
+    /// Registra `binding` si ninguna otra acción de GNOME lo usa; si no,
+    /// pregunta qué hacer.
+    fn request_binding(
+        &mut self,
+        binding: String,
+        from_wizard: bool,
+        sender: &ComponentSender<Self>,
+    ) {
+        // Con el portal, los conflictos los resuelve el escritorio
+        let conflicts = if self.portal_shortcuts {
+            Vec::new()
+        } else {
+            keybinding::conflicts(&binding)
+        };
+        if conflicts.is_empty() {
+            self.apply_binding(&binding, &[], from_wizard, sender);
+            return;
+        }
+        self.conflict_error.clear();
+        self.pending_binding = Some(PendingBinding {
+            binding,
+            conflicts,
+            from_wizard,
+        });
+        self.current_page = "binding_conflict".to_string();
+    }
+
+    fn apply_binding(
+        &mut self,
+        binding: &str,
+        disable: &[keybinding::Action],
+        from_wizard: bool,
+        sender: &ComponentSender<Self>,
+    ) {
+        self.settings.keybinding = binding.to_string();
+        self.register_binding(disable, sender);
+        self.reset_report.clear();
+        self.settings.save();
+        self.current_binding = binding.to_string();
+        *self.setup_done.borrow_mut() = true;
+
+        if from_wizard {
+            self.current_page = "clipboard".to_string();
+            if let Some(win) = gtk::Application::default().active_window() {
+                win.set_visible(false);
+            }
+        } else {
+            self.current_page = "settings".to_string();
+        }
+    }
+
+    /// Registra `settings.keybinding` en el portal o, si no hay, en GNOME
+    /// quitándoselo a las acciones de `disable`.
+    fn register_binding(&mut self, disable: &[keybinding::Action], sender: &ComponentSender<Self>) {
+        let binding = self.settings.keybinding.clone();
+        if self.portal_shortcuts {
+            self.binding_note = "pendiente de confirmar en el escritorio".to_string();
+            bind_portal_shortcut(sender.clone(), binding, self.shortcut_session.take());
+        } else {
+            let res =
+                keybinding::register(&binding, disable, &mut self.settings.overridden_bindings);
+            self.binding_note = match res {
+                Ok(()) => String::new(),
+                Err(e) => format!("GNOME no lo registró: {}", e),
+            };
+        }
+    }
+
+    fn binding_summary(&self) -> String {
+        let current = format!("Actual: {}", shortcut::label(&self.current_binding));
+        if self.binding_note.is_empty() {
+            current
+        } else {
+            format!("{} ({})", current, self.binding_note)
         }
     }
+
+    /// Qué acciones usan ya el atajo elegido.
+    fn conflict_summary(&self) -> String {
+        let Some(pending) = &self.pending_binding else {
+            return String::new();
+        };
+        let names: Vec<&str> = pending.conflicts.iter().map(|a| a.name.as_str()).collect();
+        format!(
+            "{} ya lo usa {}. Si se lo quitas, se le devolverá cuando cambies el atajo de klipBored.",
+            shortcut::label(&pending.binding),
+            names.join(", ")
+        )
+    }
+
+    /// Solo se ofrece con una acción: a varias no se les puede dar el mismo.
+    fn can_reassign(&self) -> bool {
+        self.pending_binding
+            .as_ref()
+            .is_some_and(|p| p.conflicts.len() == 1)
+    }
+
+    /// Sustituye los ajustes por los de `path` y los aplica. Devuelve lo
+    /// que hubo que corregir.
+    fn import_settings(
+        &mut self,
+        path: &Path,
+        sender: &ComponentSender<Self>,
+    ) -> Result<Vec<String>, String> {
+        let invalid = |e: String| format!("No se pudo importar {}: {}", path.display(), e);
+        let raw = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let (mut settings, mut warnings) = Settings::parse(&raw).map_err(invalid)?;
+        // Lo que no se haya entendido ya se ha perdido al leerlo
+        settings.version = settings::VERSION;
+        // Un fichero sin atajo no deja el panel sin forma de abrirse
+        if settings.keybinding.trim().is_empty() {
+            settings.keybinding = self.settings.keybinding.clone();
+        } else if let Err(e) = shortcut::validate(&settings.keybinding) {
+            warnings.push(format!(
+                "keybinding = {}: {}; se conserva el actual",
+                settings.keybinding, e
+            ));
+            settings.keybinding = self.settings.keybinding.clone();
+        }
+        // Lo que se quitó a GNOME es de este equipo, no del que exportó
+        settings.overridden_bindings = std::mem::take(&mut self.settings.overridden_bindings);
+        let binding_changed = settings.keybinding != self.settings.keybinding;
+        self.settings = settings;
+        if binding_changed {
+            self.current_binding = self.settings.keybinding.clone();
+            if !self.portal_shortcuts {
+                for action in keybinding::conflicts(&self.current_binding) {
+                    warnings.push(format!(
+                        "{} también lo usa {}",
+                        shortcut::label(&self.current_binding),
+                        action.name
+                    ));
+                }
+            }
+            self.register_binding(&[], sender);
+        }
+        self.settings.save();
+        apply_theme(self.settings.window.theme);
+        self.hide_on_focus_loss
+            .set(self.settings.window.hide_on_focus_loss);
+        self.enforce_limits();
+        self.prune_expired();
+        self.save_history();
+        Ok(warnings)
+    }
+
+    /// Deja klipBored como recién instalado (ver `reset::run`) y vuelve al
+    /// asistente. Devuelve lo que se hizo.
+    fn reset(
+        &mut self,
+        purge: bool,
+        sender: &ComponentSender<Self>,
+    ) -> Result<Vec<String>, String> {
+        let mut steps = reset::run(&mut self.settings, purge)?;
+        if let Some(session) = self.shortcut_session.take() {
+            global_shortcut::unbind(&session);
+            steps.push("Cerrado el atajo del portal".to_string());
+        }
+        if purge {
+            self.remove_where(|_| true);
+            self.store.wipe();
+            self.unlock_error.clear();
+            // La clave ya se ha olvidado; se genera otra para lo que venga
+            find_key(sender.clone(), false);
+            apply_theme(self.settings.window.theme);
+            self.hide_on_focus_loss
+                .set(self.settings.window.hide_on_focus_loss);
+        }
+        self.autostart_enabled = false;
+        self.current_binding = DEFAULT_BINDING.to_string();
+        self.recorded_binding.clear();
+        self.binding_error.clear();
+        self.binding_note.clear();
+        self.config_error.clear();
+        *self.setup_done.borrow_mut() = false;
+        self.current_page = "wizard".to_string();
+        Ok(steps)
+    }
+
+    /// Importa un archivo de `archive::export`.
+    fn import_history(&mut self, path: &Path) -> Result<String, String> {
+        let items = archive::import(path)
+            .map_err(|e| format!("No se pudo importar {}: {}", path.display(), e))?;
+        let total = items.len();
+        let added = self.import_items(items);
+        Ok(format!(
+            "{} entradas importadas ({} ya estaban)",
+            added,
+            total - added
+        ))
+    }
+
+    /// Añade las entradas que no estén ya en el historial, cada una en su
+    /// sitio según la fecha. Devuelve cuántas se añadieron.
+    fn import_items(&mut self, items: Vec<HistoryItem>) -> usize {
+        let mut added = Vec::new();
+        let mut guard = self.clipboard_entries.guard();
+        for mut item in items {
+            if guard.iter().any(|entry| entry.item.hash == item.hash) {
+                continue;
+            }
+            item.id = self.next_id;
+            self.next_id += 1;
+            self.store.add(&item);
+            let pinned = guard.iter().take_while(|entry| entry.item.pinned).count();
+            let pos = if item.pinned {
+                pinned
+            } else {
+                guard
+                    .iter()
+                    .skip(pinned)
+                    .position(|entry| entry.item.created < item.created)
+                    .map_or(guard.len(), |p| pinned + p)
+            };
+            added.push(item.id);
+            guard.insert(pos, item);
+        }
+        drop(guard);
+        if added.is_empty() {
+            return 0;
+        }
+        for &id in &added {
+            self.bus.item_added(id);
+        }
+        self.enforce_limits();
+        if !self.query.is_empty() {
+            self.apply_filter();
+        }
+        self.save_history();
+        self.prune_expired();
+        added.len()
+    }
+
+    /// Salida de `klipBored list`: una línea por entrada o JSON.
+    fn list_entries(&self, json: bool, limit: Option<usize>) -> String {
+        let entries = self
+            .clipboard_entries
+            .iter()
+            .take(limit.unwrap_or(usize::MAX))
+            .enumerate();
+        if json {
+            let items: Vec<serde_json::Value> = entries
+                .map(|(i, entry)| {
+                    let item = &entry.item;
+                    let (kind, preview) = summary(item);
+                    serde_json::json!({
+                        "index": i + 1,
+                        "id": item.id,
+                        "kind": kind,
+                        "preview": preview,
+                        "created": item.created,
+                        "pinned": item.pinned,
+                        "sensitive": item.sensitive,
+                    })
+                })
+                .collect();
+            return serde_json::to_string_pretty(&items).unwrap_or_default() + "\n";
+        }
+        let mut out = String::new();
+        for (i, entry) in entries {
+            let item = &entry.item;
+            let (kind, preview) = summary(item);
+            let preview = match kind {
+                _ if item.sensitive => "••••••••••••".to_string(),
+                "image" => format!("[imagen {}]", preview),
+                "files" => format!("[archivos] {}", preview),
+                _ => preview,
+            };
+            // Una línea por entrada, aunque el texto tenga varias
+            let line = preview.split_whitespace().collect::<Vec<_>>().join(" ");
+            let line: String = match line.char_indices().nth(CLI_PREVIEW_CHARS) {
+                Some((cut, _)) => format!("{}…", &line[..cut]),
+                None => line,
+            };
+            let pin = if item.pinned { "📌 " } else { "" };
+            out.push_str(&format!("{:>3}  {}{}\n", i + 1, pin, line));
+        }
+        out
+    }
+
+    /// Quita las entradas que cumplan `f` del historial y del disco. Devuelve
+    /// si se quitó alguna.
+    fn remove_where(&mut self, f: impl Fn(&HistoryItem) -> bool) -> bool {
+        let mut removed = false;
+        let mut guard = self.clipboard_entries.guard();
+        let mut i = guard.len();
+        while i > 0 {
+            i -= 1;
+            if !guard.get(i).is_some_and(|entry| f(&entry.item)) {
+                continue;
+            }
+            if let Some(old) = guard.remove(i) {
+                self.store.remove(&old.item);
+                self.bus.item_removed(old.item.id);
+                removed = true;
+            }
+        }
+        removed
+    }
+
+    /// Quita las entradas caducadas: por su caducidad propia, por la
+    /// antigüedad máxima de su tipo o por el borrado a medianoche.
+    fn prune_expired(&mut self) {
+        let now = history::now_unix();
+        let midnight = match self.settings.history.clear_schedule {
+            ClearSchedule::Midnight => start_of_today(),
+            _ => None,
+        };
+        let settings = self.settings.clone();
+        let expired = |item: &HistoryItem| match item.expiry {
+            Expiry::Never => false,
+            Expiry::At(t) => now >= t,
+            Expiry::Default if item.pinned => false,
+            Expiry::Default => {
+                let days = match item.content {
+                    ClipboardContent::Text { .. } => settings.history.text_max_age_days,
+                    ClipboardContent::Image { .. } => settings.history.image_max_age_days,
+                    ClipboardContent::Files { .. } => settings.history.files_max_age_days,
+                };
+                (days > 0 && now - item.created >= days as i64 * DAY)
+                    || midnight.is_some_and(|m| item.created < m)
+            }
+        };
+        if self.remove_where(expired) {
+            self.save_history();
+        }
+    }
+
+    /// Vuelve a aplicar la búsqueda a todas las entradas. Hace falta también
+    /// tras insertar o mover entradas, que se crean sin filtrar.
+    fn apply_filter(&mut self) {
+        let mut guard = self.clipboard_entries.guard();
+        for i in 0..guard.len() {
+            if let Some(entry) = guard.get_mut(i) {
+                entry.set_query(&self.query);
+            }
+        }
+        drop(guard);
+        self.clipboard_entries.widget().invalidate_filter();
+    }
+
+    /// Las fijadas están siempre al principio.
+    fn pinned_count(&self) -> usize {
+        self.clipboard_entries
+            .iter()
+            .take_while(|entry| entry.item.pinned)
+            .count()
+    }
+
+    /// Resumen para los ajustes, p. ej. "42 entradas · 12,3 MB (imágenes: 10 MB)".
+    fn usage_summary(&self) -> String {
+        let usage = Usage::of(self.clipboard_entries.iter().map(|entry| &entry.item));
+        format!(
+            "{} entradas · {} (imágenes: {})",
+            usage.entries,
+            glib::format_size(usage.bytes),
+            glib::format_size(usage.image_bytes)
+        )
+    }
+
+    fn save_history(&self) {
+        self.store
+            .save(self.clipboard_entries.iter().map(|entry| &entry.item));
+    }
 }
 
-fn setup_gsettings_binding(path_str: &str, binding: &str) {
-    let schema_custom = "org.gnome.settings-daemon.plugins.media-keys.custom-keybinding";
-    let schema_main = "org.gnome.settings-daemon.plugins.media-keys";
+/// Busca la clave del historial en otro hilo: la del llavero o, si no hay
+/// llavero, pide una contraseña. Con `forget` se olvida antes la anterior.
+fn find_key(sender: ComponentSender<KlipBoredModel>, forget: bool) {
+    std::thread::spawn(move || {
+        if forget {
+            vault::forget();
+        }
+        if vault::uses_passphrase() {
+            sender.input(KlipBoredMsg::NeedPassphrase);
+            return;
+        }
+        match vault::from_keyring() {
+            Ok(cipher) => sender.input(KlipBoredMsg::KeyReady(cipher)),
+            Err(e) => {
+                eprintln!("klipBored: llavero no disponible: {}", e);
+                sender.input(KlipBoredMsg::NeedPassphrase);
+            }
+        }
+    });
+}
 
-    // Get current list
-    let output = Command::new("gsettings")
-        .args(&["get", schema_main, "custom-keybindings"])
-        .output();
+/// Pregunta antes de algo que no se puede deshacer y manda `msg` solo si
+/// se acepta.
+fn confirm(
+    sender: &ComponentSender<KlipBoredModel>,
+    heading: &str,
+    body: &str,
+    action: &str,
+    msg: KlipBoredMsg,
+) {
+    let dialog = adw::AlertDialog::new(Some(heading), Some(body));
+    dialog.add_responses(&[("cancel", "Cancelar"), ("confirm", action)]);
+    dialog.set_response_appearance("confirm", adw::ResponseAppearance::Destructive);
+    dialog.set_default_response(Some("cancel"));
+    dialog.set_close_response("cancel");
+    let sender = sender.clone();
+    let msg = Cell::new(Some(msg));
+    dialog.connect_response(Some("confirm"), move |_, _| {
+        if let Some(msg) = msg.take() {
+            sender.input(msg);
+        }
+    });
+    dialog.present(gtk::Application::default().active_window().as_ref());
+}
 
-    let current_list_raw = if let Ok(o) = output {
-        String::from_utf8_lossy(&o.stdout).trim().to_string()
-    } else {
-        "[]".to_string()
-    };
+fn confirm_wipe(sender: &ComponentSender<KlipBoredModel>) {
+    confirm(
+        sender,
+        "¿Borrar todo el historial?",
+        "Se borran todas las entradas, también las fijadas, y se olvida la clave. No se puede deshacer.",
+        "Borrar todo",
+        KlipBoredMsg::WipeHistory,
+    );
+}
 
-    // Simple parsing of "['...', '...']"
-    let mut entries: Vec<String> = current_list_raw
-        .trim_start_matches('[')
-        .trim_end_matches(']')
-        .split(',')
-        .map(|s| s.trim().trim_matches('\'').to_string())
-        .filter(|s| !s.is_empty())
-        .collect();
-
-    let name_to_find = "klipBored";
-    let mut target_path = String::new();
-
-    // Check if it already exists
-    for path in &entries {
-        let name_output = Command::new("gsettings")
-            .args(&["get", &format!("{}:{}", schema_custom, path), "name"])
-            .output();
-        if let Ok(o) = name_output {
-            let name = String::from_utf8_lossy(&o.stdout)
-                .trim()
-                .trim_matches('\'')
-                .to_string();
-            if name == name_to_find {
-                target_path = path.clone();
-                break;
+/// Registra el atajo en el portal desde otro hilo, porque puede abrir un
+/// diálogo. El resultado llega en `ShortcutBound`.
+fn bind_portal_shortcut(
+    sender: ComponentSender<KlipBoredModel>,
+    binding: String,
+    old_session: Option<String>,
+) {
+    std::thread::spawn(move || {
+        let res =
+            global_shortcut::bind(&binding, old_session.as_deref()).map_err(|e| e.to_string());
+        sender.input(KlipBoredMsg::ShortcutBound(res));
+    });
+}
+
+/// Las 00:00 de hoy en hora local, en segundos Unix.
+fn start_of_today() -> Option<i64> {
+    let now = glib::DateTime::now_local().ok()?;
+    glib::DateTime::from_local(now.year(), now.month(), now.day_of_month(), 0, 0, 0.0)
+        .ok()
+        .map(|d| d.to_unix())
+}
+
+/// Filas que pasan el filtro de búsqueda, en orden.
+fn visible_rows(list: &gtk::ListBox) -> Vec<gtk::ListBoxRow> {
+    (0..)
+        .map_while(|i| list.row_at_index(i))
+        .filter(|row| row.is_child_visible())
+        .collect()
+}
+
+fn with_plain_fallback(content: &ClipboardContent, mut formats: Vec<MimeData>) -> Vec<MimeData> {
+    match content {
+        ClipboardContent::Text { full, .. } => {
+            for mime in ["text/plain;charset=utf-8", "text/plain"] {
+                formats.push(MimeData {
+                    mime: mime.to_string(),
+                    data: full.as_bytes().to_vec(),
+                });
             }
         }
+        ClipboardContent::Image { raw, .. } => {
+            if !formats.iter().any(|f| f.mime == "image/png") {
+                if let Some(png) = mime::encode_png(raw) {
+                    formats.push(MimeData {
+                        mime: "image/png".to_string(),
+                        data: png,
+                    });
+                }
+            }
+        }
+        ClipboardContent::Files { uris, cut } => {
+            // Se regeneran siempre para que corte/copia coincida con lo guardado
+            formats.retain(|f| !mime::FILE_MIME_TYPES.contains(&f.mime.as_str()));
+            formats.extend(mime::file_formats(uris, *cut));
+        }
+    }
+    formats
+}
+
+/// Exporta `items` a `path` y describe el resultado.
+fn export_items(path: &Path, items: &[&HistoryItem]) -> Result<String, String> {
+    if items.is_empty() {
+        return Err("No hay entradas que exportar".to_string());
     }
+    archive::export(path, items)
+        .map(|()| format!("{} entradas exportadas a {}", items.len(), path.display()))
+        .map_err(|e| format!("No se pudo exportar a {}: {}", path.display(), e))
+}
 
-    if target_path.is_empty() {
-        // Find next available index
-        let mut idx = 0;
-        loop {
-            let new_path = format!(
-                "/org/gnome/settings-daemon/plugins/media-keys/custom-keybindings/custom{}/",
-                idx
+/// Devuelve la salida de una orden y su código de salida a quien la lanzó.
+fn reply(commands: &cli::Pending, request: cli::Request, res: Result<String, String>) {
+    let Some(cmdline) = commands.take(request) else {
+        return;
+    };
+    match res {
+        Ok(out) => {
+            print_to(&cmdline, &out, false);
+            cmdline.set_exit_status(cli::EXIT_OK.into());
+        }
+        Err(e) => {
+            print_to(&cmdline, &format!("klipBored: {}\n", e), true);
+            cmdline.set_exit_status(cli::EXIT_FAILURE.into());
+        }
+    }
+}
+
+/// Escribe en la salida (o en la salida de errores) de quien lanzó la
+/// orden. `print_literal` es de GLib 2.80; con "%s" vale para las
+/// versiones anteriores (Ubuntu 22.04, Debian 12).
+fn print_to(cmdline: &gio::ApplicationCommandLine, message: &str, stderr: bool) {
+    use glib::translate::ToGlibPtr;
+    let message = std::ffi::CString::new(message.replace('\0', "")).unwrap_or_default();
+    let cmdline: *mut gio::ffi::GApplicationCommandLine = cmdline.to_glib_none().0;
+    // SAFETY: `cmdline` es válido mientras dura la llamada y el formato
+    // consume exactamente una cadena terminada en NUL
+    unsafe {
+        if stderr {
+            gio::ffi::g_application_command_line_printerr(
+                cmdline,
+                c"%s".as_ptr(),
+                message.as_ptr(),
             );
-            if !entries.contains(&new_path) {
-                target_path = new_path;
-                break;
+        } else {
+            gio::ffi::g_application_command_line_print(cmdline, c"%s".as_ptr(), message.as_ptr());
+        }
+    }
+}
+
+/// Lee entera la entrada estándar de `klipBored copy` sin bloquear la
+/// ventana y se la pasa al modelo.
+fn read_input(
+    stdin: gio::InputStream,
+    opts: cli::CopyOptions,
+    request: cli::Request,
+    commands: cli::Pending,
+    sender: ComponentSender<KlipBoredModel>,
+) {
+    glib::spawn_future_local(async move {
+        let mut data = Vec::new();
+        loop {
+            match stdin
+                .read_bytes_future(INPUT_CHUNK, glib::Priority::DEFAULT)
+                .await
+            {
+                Ok(chunk) if chunk.is_empty() => break,
+                Ok(chunk) => data.extend_from_slice(&chunk),
+                Err(e) => {
+                    let e = format!("no se pudo leer la entrada: {}", e);
+                    reply(&commands, request, Err(e));
+                    return;
+                }
             }
-            idx += 1;
         }
-        entries.push(target_path.clone());
+        sender.input(KlipBoredMsg::CopyInput(opts, data, request));
+    });
+}
 
-        // Update the main list
-        let formatted_list = format!(
-            "[{}]",
-            entries
-                .iter()
-                .map(|s| format!("'{}'", s))
-                .collect::<Vec<_>>()
-                .join(", ")
-        );
-        let _ = Command::new("gsettings")
-            .args(&["set", schema_main, "custom-keybindings", &formatted_list])
-            .status();
-    }
-
-    // Set the specific binding values
-    let _ = Command::new("gsettings")
-        .args(&[
-            "set",
-            &format!("{}:{}", schema_custom, target_path),
-            "name",
-            name_to_find,
-        ])
-        .status();
-    let _ = Command::new("gsettings")
-        .args(&[
-            "set",
-            &format!("{}:{}", schema_custom, target_path),
-            "command",
-            path_str,
-        ])
-        .status();
-    let _ = Command::new("gsettings")
-        .args(&[
-            "set",
-            &format!("{}:{}", schema_custom, target_path),
-            "binding",
-            binding,
-        ])
-        .status();
-
-    // Especial para Ubuntu: Win+V abre el calendario por defecto.
-    // Si el usuario elige Win+V, debemos deshabilitar la acción del shell.
-    // Si elige otra cosa, nos aseguramos de que la del shell esté habilitada (si era Win+V)
-    if binding == "<Super>v" {
-        let _ = Command::new("gsettings")
-            .args(&[
-                "set",
-                "org.gnome.shell.keybindings",
-                "message-list-toggle",
-                "[]",
-            ])
-            .status();
+/// Tipo MIME de lo que llega sin `-t`: texto si es UTF-8 y, si no, el que
+/// diga GIO por su contenido.
+fn guess_mime(data: &[u8]) -> String {
+    if std::str::from_utf8(data).is_ok() {
+        return "text/plain;charset=utf-8".to_string();
+    }
+    let (content_type, _) = gio::content_type_guess(None::<&str>, data);
+    gio::content_type_get_mime_type(&content_type)
+        .map_or_else(|| "application/octet-stream".to_string(), Into::into)
+}
+
+/// `klipBored reset` sin otra instancia en marcha.
+fn reset_here(purge: bool) -> u8 {
+    let (mut settings, _) = Settings::load();
+    match reset::run(&mut settings, purge) {
+        Ok(steps) => {
+            for step in steps {
+                println!("{}", step);
+            }
+            cli::EXIT_OK
+        }
+        Err(e) => {
+            eprintln!("klipBored: {}", e);
+            cli::EXIT_FAILURE
+        }
+    }
+}
+
+/// `klipBored paste`: se resuelve en este proceso, sin pasar por la
+/// instancia principal, para poder escribir datos binarios en la salida.
+/// Lo leído pasa por el mismo modelo que las entradas del historial.
+fn paste_to_stdout(opts: &cli::PasteOptions) -> u8 {
+    let selection = if opts.primary {
+        Selection::Primary
     } else {
-        // Restaurar si cambiamos a otro atajo
-        let _ = Command::new("gsettings")
-            .args(&[
-                "set",
-                "org.gnome.shell.keybindings",
-                "message-list-toggle",
-                "['<Super>v']",
-            ])
-            .status();
+        Selection::Clipboard
+    };
+    let Some(captured) = Captured::read(selection) else {
+        eprintln!("klipBored: no hay nada copiado");
+        return cli::EXIT_FAILURE;
+    };
+    if opts.list_types {
+        let mut types: Vec<String> = Vec::new();
+        for f in with_plain_fallback(&captured.content, captured.formats) {
+            if !types.contains(&f.mime) {
+                types.push(f.mime);
+            }
+        }
+        println!("{}", types.join("\n"));
+        return cli::EXIT_OK;
+    }
+    let mime = match (&opts.mime, &captured.content) {
+        (Some(mime), _) => mime.as_str(),
+        (None, ClipboardContent::Image { .. }) => "image/png",
+        (None, _) => "text/plain;charset=utf-8",
+    };
+    let Some(mut data) = content_data(&captured.content, captured.formats, mime) else {
+        eprintln!("klipBored: lo copiado no está disponible como {}", mime);
+        return cli::EXIT_FAILURE;
+    };
+    // Como wl-paste: salto de línea final en el texto salvo con -n; xclip
+    // lo deja tal cual
+    if mime.starts_with("text/") && !opts.xclip && !opts.no_newline && !data.ends_with(b"\n") {
+        data.push(b'\n');
+    }
+    match std::io::stdout().write_all(&data) {
+        Ok(()) => cli::EXIT_OK,
+        Err(e) => {
+            eprintln!("klipBored: {}", e);
+            cli::EXIT_FAILURE
+        }
+    }
+}
+
+/// Tipo de una entrada ("text", "image" o "files") y su vista previa, vacía
+/// si es sensible.
+fn summary(item: &HistoryItem) -> (&'static str, String) {
+    match &item.content {
+        ClipboardContent::Text { .. } if item.sensitive => ("text", String::new()),
+        ClipboardContent::Text { display, .. } => ("text", display.clone()),
+        ClipboardContent::Image { raw, .. } => ("image", format!("{}×{}", raw.width, raw.height)),
+        ClipboardContent::Files { uris, .. } => ("files", uris.join("\n")),
+    }
+}
+
+/// Contenido de una entrada en el tipo `mime`; si está vacío, en el
+/// principal: el texto, PNG o la lista de URIs.
+fn entry_data(item: &HistoryItem, mime: &str) -> Option<Vec<u8>> {
+    content_data(&item.content, item.formats.clone(), mime)
+}
+
+fn content_data(content: &ClipboardContent, formats: Vec<MimeData>, mime: &str) -> Option<Vec<u8>> {
+    let mime = match (content, mime) {
+        (ClipboardContent::Text { full, .. }, "") => return Some(full.as_bytes().to_vec()),
+        (ClipboardContent::Image { .. }, "") => "image/png",
+        (ClipboardContent::Files { .. }, "") => "text/uri-list",
+        (_, mime) => mime,
+    };
+    with_plain_fallback(content, formats)
+        .into_iter()
+        .find(|f| f.mime == mime)
+        .map(|f| f.data)
+}
+
+/// Una selección que amplía o recorta la anterior, hecha pocos segundos
+/// después, se considera la misma selección a medias.
+fn is_partial_selection(prev: &HistoryItem, new: &HistoryItem) -> bool {
+    if prev.source != Selection::Primary
+        || new.source != Selection::Primary
+        || new.created - prev.created > PRIMARY_MERGE_WINDOW
+    {
+        return false;
+    }
+    match (&prev.content, &new.content) {
+        (ClipboardContent::Text { full: a, .. }, ClipboardContent::Text { full: b, .. }) => {
+            a.contains(b.as_str()) || b.contains(a.as_str())
+        }
+        _ => false,
     }
 }
 
+/// Una GApplication sin GTK para pasar una orden a la instancia en marcha.
+fn command_line_app() -> gio::Application {
+    gio::Application::new(
+        Some("io.github.klipbored.app"),
+        gio::ApplicationFlags::HANDLES_COMMAND_LINE,
+    )
+}
+
 fn main() {
     glib::set_prgname(Some("io.github.klipbored.app"));
     glib::set_application_name("klipBored");
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match cli::Command::parse(&args) {
+        Ok(cli::Command::Paste(opts)) => std::process::exit(paste_to_stdout(&opts).into()),
+        Ok(cli::Command::Help) => {
+            print!("{}", cli::USAGE);
+            std::process::exit(cli::EXIT_OK.into());
+        }
+        // Sin otra instancia, esta se quedaría abierta con la orden hecha
+        Ok(command) if command.needs_instance() => {
+            let app = command_line_app();
+            let code = match app.register(gio::Cancellable::NONE) {
+                Ok(()) if app.is_remote() => app.run().get(),
+                Ok(()) => {
+                    eprintln!("klipBored: klipBored no está en marcha");
+                    cli::EXIT_FAILURE
+                }
+                Err(e) => {
+                    eprintln!("klipBored: {}", e);
+                    cli::EXIT_FAILURE
+                }
+            };
+            std::process::exit(code.into());
+        }
+        _ => {}
+    }
+
     // Register signal 2 for clean exit
     #[cfg(target_os = "linux")]
     glib::unix_signal_add(2, || {
         std::process::exit(0);
     });
 
+    // `reset` no necesita GTK, que sin pantalla (el prerm del paquete) no
+    // arranca: si hay otra instancia se le reenvía y si no se hace aquí
+    if let Ok(cli::Command::Reset { purge }) = cli::Command::parse(&args) {
+        let app = command_line_app();
+        let code = match app.register(gio::Cancellable::NONE) {
+            Ok(()) if app.is_remote() => app.run().get(),
+            _ => reset_here(purge),
+        };
+        std::process::exit(code.into());
+    }
+
     let app = adw::Application::builder()
         .application_id("io.github.klipbored.app")
-        .flags(gio::ApplicationFlags::FLAGS_NONE)
+        .flags(gio::ApplicationFlags::HANDLES_COMMAND_LINE)
+        // Para recibir `query-end` al cerrar sesión; tiene que ser antes de arrancar
+        .register_session(true)
         .build();
 
-    app.connect_startup(|_| {
+    app.connect_startup(|app| {
         load_css();
+        app.set_accels_for_action("win.show-help-overlay", &["<Control>question", "F1"]);
         // Register icon in the default icon theme so set_icon_name("klipbored") works
         if let Some(display) = gdk::Display::default() {
             let theme = gtk::IconTheme::for_display(&display);
@@ -989,15 +4091,20 @@ fn main() {
         }
     });
 
+    // Lo pone el modelo al leer los ajustes y al cambiarlos
+    let hide_on_focus_loss = Rc::new(Cell::new(true));
+    let hide = hide_on_focus_loss.clone();
     app.connect_window_added(move |_, window| {
         // Ocultar si pierde el foco
         let focus_controller = gtk::EventControllerFocus::new();
         let win_clone = window.clone();
+        let hide = hide.clone();
         focus_controller.connect_leave(move |_| {
             // Un pequeño retardo para evitar parpadeos si el foco se mueve a un submenú o similar
             let w = win_clone.clone();
+            let hide = hide.clone();
             glib::timeout_add_local(std::time::Duration::from_millis(100), move || {
-                if !w.is_active() && w.is_visible() {
+                if hide.get() && !w.is_active() && w.is_visible() {
                     w.set_visible(false);
                 }
                 glib::ControlFlow::Break
@@ -1006,5 +4113,5 @@ fn main() {
         window.add_controller(focus_controller);
     });
 
-    RelmApp::from_app(app).run::<KlipBoredModel>(());
+    RelmApp::from_app(app).run::<KlipBoredModel>(hide_on_focus_loss);
 }
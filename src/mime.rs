@@ -0,0 +1,427 @@
+//! Representaciones MIME adicionales de una entrada (HTML, RTF, imágenes
+//! codificadas...).
+//!
+//! `arboard` solo sabe de texto plano e imágenes RGBA, así que estas se
+//! leen y escriben directamente: con data-control (vía `wl-clipboard-rs`)
+//! en Wayland y con el protocolo de selecciones de X11 en el resto de casos.
+
+use crate::settings::CopyTarget;
+use crate::watcher::Selection;
+use crate::ImageDataOwned;
+use gtk::prelude::*;
+use gtk::{gdk, gio, glib};
+use image::ImageEncoder;
+use std::io::Read;
+use wl_clipboard_rs::{copy, paste};
+
+/// Tipos que merece la pena guardar además del texto plano / RGBA.
+pub const RICH_MIME_TYPES: &[&str] = &[
+    "text/html",
+    "text/rtf",
+    "application/rtf",
+    "text/richtext",
+    "image/png",
+    "image/jpeg",
+    "image/svg+xml",
+    "image/gif",
+    "image/webp",
+];
+
+/// Tipos con los que los gestores de archivos copian ficheros.
+pub const FILE_MIME_TYPES: &[&str] = &[
+    "x-special/gnome-copied-files",
+    "text/uri-list",
+    "application/x-kde-cutselection",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MimeData {
+    pub mime: String,
+    pub data: Vec<u8>,
+}
+
+/// Etiqueta corta para mostrar en la fila del historial.
+pub fn short_label(mime: &str) -> Option<&'static str> {
+    match mime {
+        "text/html" => Some("HTML"),
+        "text/rtf" | "application/rtf" | "text/richtext" => Some("RTF"),
+        "image/png" => Some("PNG"),
+        "image/jpeg" => Some("JPEG"),
+        "image/svg+xml" => Some("SVG"),
+        "image/gif" => Some("GIF"),
+        "image/webp" => Some("WebP"),
+        _ => None,
+    }
+}
+
+pub fn encode_png(raw: &ImageDataOwned) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    image::codecs::png::PngEncoder::new(&mut out)
+        .write_image(
+            &raw.data,
+            raw.width as u32,
+            raw.height as u32,
+            image::ExtendedColorType::Rgba8,
+        )
+        .ok()?;
+    Some(out)
+}
+
+/// Lee de la selección indicada todas las representaciones de
+/// `RICH_MIME_TYPES` que ofrezca su dueño.
+pub fn read(selection: Selection) -> Vec<MimeData> {
+    read_types(selection, RICH_MIME_TYPES)
+}
+
+/// Como `read`, pero solo con los tipos de ficheros copiados.
+pub fn read_files(selection: Selection) -> Vec<MimeData> {
+    read_types(selection, FILE_MIME_TYPES)
+}
+
+/// Los avisos de contenido secreto de `sensitive::HINT_MIME_TYPES`.
+pub fn read_hints(selection: Selection) -> Vec<MimeData> {
+    read_types(selection, crate::sensitive::HINT_MIME_TYPES)
+}
+
+fn read_types(selection: Selection, types: &[&str]) -> Vec<MimeData> {
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        if let Some(formats) = wayland::read(selection, types) {
+            return formats;
+        }
+    }
+    if std::env::var_os("DISPLAY").is_some() {
+        return x11::read(selection, types).unwrap_or_default();
+    }
+    Vec::new()
+}
+
+/// Extrae la lista de ficheros copiados y si se cortaron en vez de copiarse.
+///
+/// Nautilus manda `x-special/gnome-copied-files` ("copy" o "cut" y una URI
+/// por línea); Dolphin y el resto, `text/uri-list` más
+/// `application/x-kde-cutselection` a "1" cuando es un corte. Solo se
+/// aceptan URIs `file://`, que son las que se pueden volver a pegar.
+pub fn parse_files(formats: &[MimeData]) -> Option<(Vec<String>, bool)> {
+    let find = |mime: &str| {
+        formats
+            .iter()
+            .find(|f| f.mime == mime)
+            .map(|f| String::from_utf8_lossy(&f.data).into_owned())
+    };
+
+    let (uris, cut): (Vec<&str>, bool);
+    let gnome = find("x-special/gnome-copied-files");
+    let uri_list = find("text/uri-list");
+    if let Some(raw) = &gnome {
+        let mut lines = raw.lines();
+        cut = lines.next().map(str::trim) == Some("cut");
+        uris = lines.collect();
+    } else if let Some(raw) = &uri_list {
+        cut = find("application/x-kde-cutselection").is_some_and(|v| v.trim() == "1");
+        // RFC 2483: las líneas que empiezan por '#' son comentarios
+        uris = raw.lines().filter(|l| !l.starts_with('#')).collect();
+    } else {
+        return None;
+    }
+
+    let uris: Vec<String> = uris
+        .into_iter()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect();
+    if uris.is_empty() || !uris.iter().all(|u| u.starts_with("file://")) {
+        return None;
+    }
+    Some((uris, cut))
+}
+
+/// Las representaciones que esperan los gestores de archivos para pegar
+/// los ficheros, más sus rutas como texto plano para el resto.
+pub fn file_formats(uris: &[String], cut: bool) -> Vec<MimeData> {
+    let action = if cut { "cut" } else { "copy" };
+    let mut gnome = action.to_string();
+    for uri in uris {
+        gnome.push('\n');
+        gnome.push_str(uri);
+    }
+    let uri_list: String = uris.iter().map(|u| format!("{}\r\n", u)).collect();
+    let paths = uris
+        .iter()
+        .map(|u| {
+            gio::File::for_uri(u)
+                .path()
+                .map_or_else(|| u.clone(), |p| p.to_string_lossy().into_owned())
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut formats = vec![
+        MimeData {
+            mime: "x-special/gnome-copied-files".to_string(),
+            data: gnome.into_bytes(),
+        },
+        MimeData {
+            mime: "text/uri-list".to_string(),
+            data: uri_list.into_bytes(),
+        },
+        MimeData {
+            mime: "application/x-kde-cutselection".to_string(),
+            data: if cut { b"1".to_vec() } else { b"0".to_vec() },
+        },
+    ];
+    for mime in ["text/plain;charset=utf-8", "text/plain"] {
+        formats.push(MimeData {
+            mime: mime.to_string(),
+            data: paths.as_bytes().to_vec(),
+        });
+    }
+    formats
+}
+
+/// Pone en el portapapeles todas las representaciones a la vez.
+///
+/// Con data-control lo sirve `wl-clipboard-rs` desde su propio hilo; si no,
+/// se usa el portapapeles de GDK, que debe llamarse desde el hilo principal.
+pub fn write(formats: &[MimeData], target: CopyTarget) {
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() && wayland::write(formats, target) {
+        return;
+    }
+
+    let Some(display) = gdk::Display::default() else {
+        return;
+    };
+    let providers: Vec<gdk::ContentProvider> = formats
+        .iter()
+        .map(|f| gdk::ContentProvider::for_bytes(&f.mime, &glib::Bytes::from(&f.data)))
+        .collect();
+    let provider = gdk::ContentProvider::new_union(&providers);
+    if target != CopyTarget::Primary {
+        let _ = display.clipboard().set_content(Some(&provider));
+    }
+    if target != CopyTarget::Clipboard {
+        let _ = display.primary_clipboard().set_content(Some(&provider));
+    }
+}
+
+mod wayland {
+    use super::*;
+
+    fn clipboard_type(selection: Selection) -> paste::ClipboardType {
+        match selection {
+            Selection::Clipboard => paste::ClipboardType::Regular,
+            Selection::Primary => paste::ClipboardType::Primary,
+        }
+    }
+
+    /// `None` si el compositor no tiene data-control.
+    pub fn read(selection: Selection, types: &[&str]) -> Option<Vec<MimeData>> {
+        let clipboard = clipboard_type(selection);
+        let available = match paste::get_mime_types(clipboard, paste::Seat::Unspecified) {
+            Ok(types) => types,
+            Err(paste::Error::MissingProtocol { .. })
+            | Err(paste::Error::WaylandConnection(_))
+            | Err(paste::Error::PrimarySelectionUnsupported) => return None,
+            Err(_) => return Some(Vec::new()),
+        };
+
+        let mut formats = Vec::new();
+        for mime in types.iter().filter(|m| available.contains(**m)) {
+            let res = paste::get_contents(
+                clipboard,
+                paste::Seat::Unspecified,
+                paste::MimeType::Specific(mime),
+            );
+            let mut data = Vec::new();
+            if let Ok((mut pipe, _)) = res {
+                if pipe.read_to_end(&mut data).is_ok() && !data.is_empty() {
+                    formats.push(MimeData {
+                        mime: mime.to_string(),
+                        data,
+                    });
+                }
+            }
+        }
+        Some(formats)
+    }
+
+    pub fn write(formats: &[MimeData], target: CopyTarget) -> bool {
+        let mut opts = copy::Options::new();
+        opts.clipboard(match target {
+            CopyTarget::Clipboard => copy::ClipboardType::Regular,
+            CopyTarget::Primary => copy::ClipboardType::Primary,
+            CopyTarget::Both => copy::ClipboardType::Both,
+        });
+        let sources = formats
+            .iter()
+            .map(|f| copy::MimeSource {
+                source: copy::Source::Bytes(f.data.clone().into_boxed_slice()),
+                mime_type: copy::MimeType::Specific(f.mime.clone()),
+            })
+            .collect();
+        opts.copy_multi(sources).is_ok()
+    }
+}
+
+mod x11 {
+    use super::*;
+    use std::error::Error;
+    use std::time::{Duration, Instant};
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xproto::{
+        Atom, AtomEnum, ConnectionExt as _, CreateWindowAux, EventMask, Property, Window,
+        WindowClass,
+    };
+    use x11rb::protocol::Event;
+    use x11rb::rust_connection::RustConnection;
+    use x11rb::{COPY_DEPTH_FROM_PARENT, CURRENT_TIME, NONE};
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    struct Reader {
+        conn: RustConnection,
+        win: Window,
+        selection: Atom,
+        property: Atom,
+        incr: Atom,
+    }
+
+    pub fn read(selection: Selection, types: &[&str]) -> Result<Vec<MimeData>, Box<dyn Error>> {
+        let (conn, screen_num) = x11rb::connect(None)?;
+        let screen = &conn.setup().roots[screen_num];
+        let win = conn.generate_id()?;
+        conn.create_window(
+            COPY_DEPTH_FROM_PARENT,
+            win,
+            screen.root,
+            0,
+            0,
+            1,
+            1,
+            0,
+            WindowClass::INPUT_ONLY,
+            NONE,
+            &CreateWindowAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+        )?;
+
+        let selection = match selection {
+            Selection::Clipboard => conn.intern_atom(false, b"CLIPBOARD")?.reply()?.atom,
+            Selection::Primary => AtomEnum::PRIMARY.into(),
+        };
+        let property = conn.intern_atom(false, b"KLIPBORED_DATA")?.reply()?.atom;
+        let incr = conn.intern_atom(false, b"INCR")?.reply()?.atom;
+        let reader = Reader {
+            property,
+            incr,
+            selection,
+            win,
+            conn,
+        };
+
+        let targets_atom = reader.conn.intern_atom(false, b"TARGETS")?.reply()?.atom;
+        let targets: Vec<Atom> = match reader.fetch(targets_atom)? {
+            Some(raw) => raw
+                .chunks_exact(4)
+                .map(|c| u32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+                .collect(),
+            None => return Ok(Vec::new()),
+        };
+
+        let mut formats = Vec::new();
+        for mime in types {
+            let atom = reader
+                .conn
+                .intern_atom(true, mime.as_bytes())?
+                .reply()?
+                .atom;
+            if atom == NONE || !targets.contains(&atom) {
+                continue;
+            }
+            if let Some(data) = reader.fetch(atom)? {
+                if !data.is_empty() {
+                    formats.push(MimeData {
+                        mime: mime.to_string(),
+                        data,
+                    });
+                }
+            }
+        }
+        Ok(formats)
+    }
+
+    impl Reader {
+        /// Pide la selección convertida a `target` y espera la respuesta,
+        /// incluidas las transferencias INCR de los datos grandes.
+        fn fetch(&self, target: Atom) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+            self.conn.convert_selection(
+                self.win,
+                self.selection,
+                target,
+                self.property,
+                CURRENT_TIME,
+            )?;
+            self.conn.flush()?;
+
+            let deadline = Instant::now() + TIMEOUT;
+            loop {
+                match self.next_event(deadline)? {
+                    Some(Event::SelectionNotify(ev)) if ev.property == NONE => return Ok(None),
+                    Some(Event::SelectionNotify(_)) => break,
+                    Some(_) => {}
+                    None => return Ok(None),
+                }
+            }
+
+            let reply = self
+                .conn
+                .get_property(true, self.win, self.property, AtomEnum::ANY, 0, u32::MAX)?
+                .reply()?;
+            if reply.type_ != self.incr {
+                return Ok(Some(reply.value));
+            }
+
+            // INCR: el dueño manda trozos cada vez que borramos la propiedad,
+            // y termina con uno vacío.
+            self.conn.flush()?;
+            let mut data = Vec::new();
+            loop {
+                let deadline = Instant::now() + TIMEOUT;
+                match self.next_event(deadline)? {
+                    Some(Event::PropertyNotify(ev))
+                        if ev.atom == self.property && ev.state == Property::NEW_VALUE =>
+                    {
+                        let chunk = self
+                            .conn
+                            .get_property(
+                                true,
+                                self.win,
+                                self.property,
+                                AtomEnum::ANY,
+                                0,
+                                u32::MAX,
+                            )?
+                            .reply()?;
+                        self.conn.flush()?;
+                        if chunk.value.is_empty() {
+                            return Ok(Some(data));
+                        }
+                        data.extend_from_slice(&chunk.value);
+                    }
+                    Some(_) => {}
+                    None => return Ok(None),
+                }
+            }
+        }
+
+        fn next_event(&self, deadline: Instant) -> Result<Option<Event>, Box<dyn Error>> {
+            loop {
+                if let Some(ev) = self.conn.poll_for_event()? {
+                    return Ok(Some(ev));
+                }
+                if Instant::now() >= deadline {
+                    return Ok(None);
+                }
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        }
+    }
+}
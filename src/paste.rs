@@ -0,0 +1,295 @@
+//! Pegado automático en la ventana que tenía el foco.
+//!
+//! Tras recuperar una entrada y ocultarse la ventana se simula la
+//! pulsación de Ctrl+V (o Ctrl+Shift+V en terminales). Cada servidor
+//! gráfico tiene su forma de hacerlo: XTest en X11 y, en Wayland, el
+//! protocolo virtual-keyboard de wlroots o el portal RemoteDesktop en
+//! GNOME y KDE, que la primera vez pide permiso al usuario.
+
+use crate::history::data_dir;
+use crate::portal::{self, Portal};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::time::Duration;
+
+// Keysyms de X11, que también usa el portal
+const XK_CONTROL_L: u32 = 0xffe3;
+const XK_SHIFT_L: u32 = 0xffe1;
+const XK_V: u32 = 0x0076;
+
+/// Combinación con la que se pega una entrada.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PasteKeys {
+    #[default]
+    CtrlV,
+    /// La de la mayoría de terminales.
+    CtrlShiftV,
+}
+
+impl PasteKeys {
+    fn keysyms(self) -> &'static [u32] {
+        match self {
+            PasteKeys::CtrlV => &[XK_CONTROL_L, XK_V],
+            PasteKeys::CtrlShiftV => &[XK_CONTROL_L, XK_SHIFT_L, XK_V],
+        }
+    }
+}
+
+/// Pulsa la combinación pasado `delay`, para dar tiempo a que el foco
+/// vuelva a la ventana anterior. No bloquea.
+pub fn send(keys: PasteKeys, delay: Duration) {
+    std::thread::spawn(move || {
+        std::thread::sleep(delay);
+        let res = if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+            wayland::send(keys).or_else(|e| {
+                eprintln!("klipBored: virtual-keyboard no disponible: {}", e);
+                remote_desktop::send(keys)
+            })
+        } else if std::env::var_os("DISPLAY").is_some() {
+            x11::send(keys)
+        } else {
+            Err("no hay servidor gráfico".into())
+        };
+        if let Err(e) = res {
+            eprintln!("klipBored: no se pudo pegar: {}", e);
+        }
+    });
+}
+
+mod x11 {
+    use super::*;
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xproto::{ConnectionExt as _, KEY_PRESS_EVENT, KEY_RELEASE_EVENT};
+    use x11rb::protocol::xtest::ConnectionExt as _;
+    use x11rb::CURRENT_TIME;
+
+    pub fn send(keys: PasteKeys) -> Result<(), Box<dyn Error>> {
+        let (conn, screen_num) = x11rb::connect(None)?;
+        conn.xtest_get_version(2, 2)?.reply()?;
+        let setup = conn.setup();
+        let root = setup.roots[screen_num].root;
+        let (min, max) = (setup.min_keycode, setup.max_keycode);
+        let mapping = conn.get_keyboard_mapping(min, max - min + 1)?.reply()?;
+        let per_keycode = mapping.keysyms_per_keycode as usize;
+
+        let keycodes = keys
+            .keysyms()
+            .iter()
+            .map(|&sym| {
+                mapping
+                    .keysyms
+                    .chunks(per_keycode)
+                    .position(|syms| syms.contains(&sym))
+                    .map(|i| min + i as u8)
+                    .ok_or_else(|| format!("ninguna tecla produce {:#x}", sym))
+            })
+            .collect::<Result<Vec<u8>, _>>()?;
+
+        for &code in &keycodes {
+            conn.xtest_fake_input(KEY_PRESS_EVENT, code, CURRENT_TIME, root, 0, 0, 0)?;
+        }
+        for &code in keycodes.iter().rev() {
+            conn.xtest_fake_input(KEY_RELEASE_EVENT, code, CURRENT_TIME, root, 0, 0, 0)?;
+        }
+        // Espera a que el servidor procese las pulsaciones antes de cerrar
+        conn.get_input_focus()?.reply()?;
+        Ok(())
+    }
+}
+
+mod wayland {
+    use super::*;
+    use std::io::Write;
+    use std::os::fd::AsFd;
+    use std::time::Instant;
+    use wayland_client::globals::{registry_queue_init, GlobalListContents};
+    use wayland_client::protocol::{wl_keyboard::KeyState, wl_registry, wl_seat::WlSeat};
+    use wayland_client::{delegate_noop, Connection, Dispatch, EventQueue, QueueHandle};
+    use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::{
+        zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1,
+        zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1,
+    };
+
+    // Códigos evdev; en el mapa de teclado de abajo llevan 8 más
+    const KEY_LEFTCTRL: u32 = 29;
+    const KEY_LEFTSHIFT: u32 = 42;
+    const KEY_V: u32 = 47;
+    // Máscaras de modificadores del mapa de teclado
+    const MOD_SHIFT: u32 = 1;
+    const MOD_CONTROL: u32 = 4;
+
+    /// Mapa mínimo con solo las teclas que usamos.
+    const KEYMAP: &str = "xkb_keymap {
+xkb_keycodes \"klipbored\" { minimum = 8; maximum = 255; <LCTL> = 37; <LFSH> = 50; <AB04> = 55; };
+xkb_types \"klipbored\" { include \"complete\" };
+xkb_compatibility \"klipbored\" { include \"complete\" };
+xkb_symbols \"klipbored\" {
+    key <LCTL> { [ Control_L ] };
+    key <LFSH> { [ Shift_L ] };
+    key <AB04> { [ v, V ] };
+    modifier_map Control { <LCTL> };
+    modifier_map Shift { <LFSH> };
+};
+};
+";
+
+    struct State;
+
+    pub fn send(keys: PasteKeys) -> Result<(), Box<dyn Error>> {
+        let conn = Connection::connect_to_env()?;
+        let (globals, mut queue): (_, EventQueue<State>) = registry_queue_init(&conn)?;
+        let qh = queue.handle();
+        let seat: WlSeat = globals.bind(&qh, 1..=8, ())?;
+        let manager: ZwpVirtualKeyboardManagerV1 = globals.bind(&qh, 1..=1, ())?;
+        let keyboard = manager.create_virtual_keyboard(&seat, &qh, ());
+
+        // El mapa se pasa como fichero; basta con que exista mientras se lee
+        let path =
+            gtk::glib::user_runtime_dir().join(format!("klipbored-keymap-{}", std::process::id()));
+        let mut file = fs::File::create(&path)?;
+        file.write_all(KEYMAP.as_bytes())?;
+        file.write_all(b"\0")?;
+        let file = fs::File::open(&path)?;
+        let _ = fs::remove_file(&path);
+        keyboard.keymap(1, file.as_fd(), KEYMAP.len() as u32 + 1); // 1 = XKB_V1
+
+        let start = Instant::now();
+        let time = || start.elapsed().as_millis() as u32;
+        let mut mods = 0;
+        let codes: Vec<(u32, u32)> = keys
+            .keysyms()
+            .iter()
+            .map(|&sym| match sym {
+                XK_CONTROL_L => (KEY_LEFTCTRL, MOD_CONTROL),
+                XK_SHIFT_L => (KEY_LEFTSHIFT, MOD_SHIFT),
+                _ => (KEY_V, 0),
+            })
+            .collect();
+        for &(code, modifier) in &codes {
+            keyboard.key(time(), code, KeyState::Pressed.into());
+            if modifier != 0 {
+                mods |= modifier;
+                keyboard.modifiers(mods, 0, 0, 0);
+            }
+        }
+        for &(code, modifier) in codes.iter().rev() {
+            keyboard.key(time(), code, KeyState::Released.into());
+            if modifier != 0 {
+                mods &= !modifier;
+                keyboard.modifiers(mods, 0, 0, 0);
+            }
+        }
+        queue.roundtrip(&mut State)?;
+        keyboard.destroy();
+        queue.roundtrip(&mut State)?;
+        Ok(())
+    }
+
+    impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
+        fn event(
+            _: &mut Self,
+            _: &wl_registry::WlRegistry,
+            _: wl_registry::Event,
+            _: &GlobalListContents,
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    delegate_noop!(State: ignore WlSeat);
+    delegate_noop!(State: ZwpVirtualKeyboardManagerV1);
+    delegate_noop!(State: ZwpVirtualKeyboardV1);
+}
+
+mod remote_desktop {
+    use super::*;
+    use gtk::glib::{self, variant::ToVariant};
+
+    const INTERFACE: &str = "org.freedesktop.portal.RemoteDesktop";
+    const DEVICE_KEYBOARD: u32 = 1;
+    // Que el permiso se recuerde hasta que el usuario lo retire
+    const PERSIST_PERMANENT: u32 = 2;
+
+    fn token_file() -> std::path::PathBuf {
+        data_dir().join("remote-desktop-token")
+    }
+
+    pub fn send(keys: PasteKeys) -> Result<(), Box<dyn Error>> {
+        Portal::with(|portal| {
+            let results = portal.request(INTERFACE, "CreateSession", |token| {
+                glib::Variant::tuple_from_iter([portal::options(&[
+                    ("handle_token", token.to_variant()),
+                    ("session_handle_token", Portal::token().to_variant()),
+                ])])
+            })?;
+            let session: String = results
+                .lookup("session_handle")?
+                .ok_or("el portal no devolvió la sesión")?;
+            let session_path = portal::object_path(&session)?;
+
+            let restore_token = fs::read_to_string(token_file()).unwrap_or_default();
+            portal.request(INTERFACE, "SelectDevices", |token| {
+                let mut options = vec![
+                    ("handle_token", token.to_variant()),
+                    ("types", DEVICE_KEYBOARD.to_variant()),
+                    ("persist_mode", PERSIST_PERMANENT.to_variant()),
+                ];
+                if !restore_token.trim().is_empty() {
+                    options.push(("restore_token", restore_token.trim().to_variant()));
+                }
+                glib::Variant::tuple_from_iter([
+                    session_path.to_variant(),
+                    portal::options(&options),
+                ])
+            })?;
+
+            let results = portal.request(INTERFACE, "Start", |token| {
+                glib::Variant::tuple_from_iter([
+                    session_path.to_variant(),
+                    "".to_variant(),
+                    portal::options(&[("handle_token", token.to_variant())]),
+                ])
+            })?;
+            // El token solo vale una vez; cada sesión devuelve el siguiente
+            if let Ok(Some(token)) = results.lookup::<String>("restore_token") {
+                let _ = fs::create_dir_all(data_dir());
+                let _ = fs::write(token_file(), token);
+            }
+
+            let notify = |keysym: u32, pressed: bool| {
+                portal.call(
+                    INTERFACE,
+                    "NotifyKeyboardKeysym",
+                    &glib::Variant::tuple_from_iter([
+                        session_path.to_variant(),
+                        portal::options(&[]),
+                        (keysym as i32).to_variant(),
+                        (pressed as u32).to_variant(),
+                    ]),
+                )
+            };
+            let res = keys
+                .keysyms()
+                .iter()
+                .map(|&sym| (sym, true))
+                .chain(keys.keysyms().iter().rev().map(|&sym| (sym, false)))
+                .try_for_each(|(sym, pressed)| notify(sym, pressed).map(drop));
+
+            let _ = portal.conn.call_sync(
+                Some("org.freedesktop.portal.Desktop"),
+                &session,
+                "org.freedesktop.portal.Session",
+                "Close",
+                None,
+                None,
+                gtk::gio::DBusCallFlags::NONE,
+                -1,
+                gtk::gio::Cancellable::NONE,
+            );
+            Ok(res?)
+        })
+    }
+}
@@ -0,0 +1,138 @@
+//! Llamadas a los portales de escritorio (`org.freedesktop.portal.*`).
+//!
+//! Los métodos de los portales no responden directamente: devuelven un
+//! objeto `Request` y el resultado llega después en su señal `Response`,
+//! a veces tras un diálogo con el usuario. `Portal` lo envuelve en llamadas
+//! bloqueantes, así que debe usarse desde un hilo propio y no desde el de
+//! la interfaz.
+
+use gtk::{gio, glib};
+use std::cell::RefCell;
+use std::error::Error;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+pub const DESTINATION: &str = "org.freedesktop.portal.Desktop";
+pub const OBJECT_PATH: &str = "/org/freedesktop/portal/desktop";
+// Puede haber un diálogo de por medio; se le da tiempo al usuario
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+
+static NEXT_TOKEN: AtomicU32 = AtomicU32::new(0);
+
+/// Conexión al bus de sesión con su propio contexto de GLib, donde se
+/// reciben las señales.
+pub struct Portal {
+    pub conn: gio::DBusConnection,
+    ctx: glib::MainContext,
+}
+
+impl Portal {
+    /// Ejecuta `f` con un `Portal` cuyo contexto es el predeterminado del
+    /// hilo mientras dura la llamada.
+    pub fn with<R>(
+        f: impl FnOnce(&Portal) -> Result<R, Box<dyn Error>>,
+    ) -> Result<R, Box<dyn Error>> {
+        let ctx = glib::MainContext::new();
+        ctx.with_thread_default(|| {
+            let conn = gio::bus_get_sync(gio::BusType::Session, gio::Cancellable::NONE)?;
+            f(&Portal {
+                conn,
+                ctx: ctx.clone(),
+            })
+        })?
+    }
+
+    /// Token nuevo para `handle_token` o `session_handle_token`.
+    pub fn token() -> String {
+        format!("klipbored{}", NEXT_TOKEN.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// Llamada normal, para los métodos que responden directamente.
+    pub fn call(
+        &self,
+        interface: &str,
+        method: &str,
+        args: &glib::Variant,
+    ) -> Result<glib::Variant, glib::Error> {
+        self.conn.call_sync(
+            Some(DESTINATION),
+            OBJECT_PATH,
+            interface,
+            method,
+            Some(args),
+            None,
+            gio::DBusCallFlags::NONE,
+            -1,
+            gio::Cancellable::NONE,
+        )
+    }
+
+    /// Llama a un método que responde con un `Request` y espera a su
+    /// `Response`. `args` recibe el `handle_token` que tiene que ir en las
+    /// opciones de la llamada.
+    pub fn request(
+        &self,
+        interface: &str,
+        method: &str,
+        args: impl FnOnce(&str) -> glib::Variant,
+    ) -> Result<glib::VariantDict, Box<dyn Error>> {
+        let token = Self::token();
+        // La ruta del Request se conoce de antemano, así que nos suscribimos
+        // antes de llamar para no perder una respuesta inmediata
+        let sender = self
+            .conn
+            .unique_name()
+            .ok_or("sin nombre en el bus")?
+            .trim_start_matches(':')
+            .replace('.', "_");
+        let path = format!("{}/request/{}/{}", OBJECT_PATH, sender, token);
+
+        let response: Rc<RefCell<Option<(u32, glib::VariantDict)>>> = Rc::default();
+        let response_cb = response.clone();
+        let _subscription = self.conn.subscribe_to_signal(
+            Some(DESTINATION),
+            Some("org.freedesktop.portal.Request"),
+            Some("Response"),
+            Some(&path),
+            None,
+            gio::DBusSignalFlags::NONE,
+            move |signal| {
+                *response_cb.borrow_mut() = signal.parameters.get();
+            },
+        );
+
+        self.call(interface, method, &args(&token))?;
+
+        let deadline = Instant::now() + RESPONSE_TIMEOUT;
+        while response.borrow().is_none() {
+            if Instant::now() >= deadline {
+                return Err(format!("{}.{}: sin respuesta", interface, method).into());
+            }
+            if !self.ctx.iteration(false) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+        match response.take() {
+            Some((0, results)) => Ok(results),
+            Some((1, _)) => {
+                Err(format!("{}.{}: cancelado por el usuario", interface, method).into())
+            }
+            _ => Err(format!("{}.{}: error del portal", interface, method).into()),
+        }
+    }
+}
+
+/// Opciones `a{sv}` de una llamada a un portal.
+pub fn options(entries: &[(&str, glib::Variant)]) -> glib::Variant {
+    let dict = glib::VariantDict::new(None);
+    for (key, value) in entries {
+        dict.insert_value(key, value);
+    }
+    dict.end()
+}
+
+/// Convierte la ruta de una sesión en el tipo `o` de D-Bus.
+pub fn object_path(path: &str) -> Result<glib::variant::ObjectPath, Box<dyn Error>> {
+    Ok(glib::variant::ObjectPath::try_from(path)?)
+}
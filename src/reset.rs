@@ -0,0 +1,65 @@
+//! Deshace lo que klipBored cambia fuera de su ventana: el atajo de GNOME,
+//! los atajos que se quitaron a otras acciones y el inicio automático. Con
+//! `purge` borra además el historial, su clave y los ajustes.
+//!
+//! Lo usan `klipBored reset`, el botón de los ajustes, `uninstall.sh` y el
+//! `prerm` del paquete. Sin otra instancia en marcha se hace en el propio
+//! proceso, sin abrir la ventana.
+
+use crate::settings::{self, Settings};
+use crate::{autostart_file, history, keybinding, vault};
+use std::fs;
+use std::path::Path;
+
+/// Deshace todo y devuelve lo que se hizo, un paso por línea. Si GNOME no
+/// deja devolver los atajos se para ahí, sin tocar `settings`, para no
+/// perder lo que había apuntado.
+pub fn run(settings: &mut Settings, purge: bool) -> Result<Vec<String>, String> {
+    let mut steps = Vec::new();
+    let registered = keybinding::is_registered();
+    let restored = settings.overridden_bindings.clone();
+    keybinding::unregister(&mut settings.overridden_bindings)
+        .map_err(|e| format!("no se pudo devolver el atajo a GNOME: {}", e))?;
+    if registered {
+        steps.push("Quitado el atajo personalizado de GNOME".to_string());
+    }
+    for action in restored {
+        steps.push(format!("Devuelto {} a {}", action.value, action.name));
+    }
+
+    let autostart = autostart_file();
+    if autostart.exists() {
+        steps.push(remove(&autostart, "Inicio automático", |path| {
+            fs::remove_file(path)
+        }));
+    }
+
+    if purge {
+        *settings = Settings::default();
+        vault::forget();
+        steps.push("Olvidada la clave del historial".to_string());
+        steps.push(remove(&history::data_dir(), "Historial", |path| {
+            fs::remove_dir_all(path)
+        }));
+        steps.push(remove(&settings::config_dir(), "Ajustes", |path| {
+            fs::remove_dir_all(path)
+        }));
+    } else {
+        // El resto de ajustes se conserva; sin atajo se vuelve al asistente
+        settings.keybinding.clear();
+        settings.overridden_bindings.clear();
+        settings.save();
+        steps.push("Quitado el atajo de los ajustes; el resto se conserva".to_string());
+    }
+    Ok(steps)
+}
+
+fn remove(path: &Path, what: &str, f: fn(&Path) -> std::io::Result<()>) -> String {
+    match f(path) {
+        Ok(()) => format!("{}: borrado {}", what, path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            format!("{}: no había nada en {}", what, path.display())
+        }
+        Err(e) => format!("{}: no se pudo borrar {}: {}", what, path.display(), e),
+    }
+}
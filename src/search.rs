@@ -0,0 +1,105 @@
+//! Búsqueda aproximada sobre el historial.
+//!
+//! Cada palabra de la búsqueda tiene que aparecer en el texto con sus
+//! letras en orden, sin distinguir mayúsculas, y con pocos caracteres de
+//! por medio entre una letra y la siguiente: así "clpbrd" encuentra
+//! "clipboard", pero tres letras sueltas no casan con cualquier texto largo.
+
+use gtk::glib;
+
+/// Caracteres que puede haber entre dos letras seguidas de una palabra.
+const MAX_GAP: usize = 3;
+
+/// Posiciones (en bytes) de los caracteres de `text` que casan con `query`,
+/// ordenadas, o `None` si alguna palabra no aparece.
+pub fn find(text: &str, query: &str) -> Option<Vec<usize>> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut matched = Vec::new();
+    for word in query.split_whitespace() {
+        let word: Vec<char> = word.chars().collect();
+        matched.extend(find_word(&chars, &word)?);
+    }
+    matched.sort_unstable();
+    matched.dedup();
+    Some(matched)
+}
+
+/// Primera aparición de `word` en `chars`, preferiblemente seguida.
+fn find_word(chars: &[(usize, char)], word: &[char]) -> Option<Vec<usize>> {
+    let Some(&first) = word.first() else {
+        return Some(Vec::new());
+    };
+    let starts = || {
+        chars
+            .iter()
+            .enumerate()
+            .filter(move |(_, &(_, c))| same_char(c, first))
+            .map(|(i, _)| i)
+    };
+    // Una coincidencia exacta gana a cualquier otra aunque esté más adelante
+    for start in starts() {
+        let window = chars.get(start..start + word.len());
+        if window.is_some_and(|w| w.iter().zip(word).all(|(&(_, c), &q)| same_char(c, q))) {
+            return Some(
+                chars[start..start + word.len()]
+                    .iter()
+                    .map(|&(b, _)| b)
+                    .collect(),
+            );
+        }
+    }
+    starts().find_map(|start| {
+        let mut positions = vec![chars[start].0];
+        let mut i = start;
+        for &q in &word[1..] {
+            let next = chars[i + 1..]
+                .iter()
+                .take(MAX_GAP + 1)
+                .position(|&(_, c)| same_char(c, q))?;
+            i += next + 1;
+            positions.push(chars[i].0);
+        }
+        Some(positions)
+    })
+}
+
+fn same_char(a: char, b: char) -> bool {
+    a == b || a.to_lowercase().eq(b.to_lowercase())
+}
+
+/// Marcado de Pango de `text` con los caracteres en `matched` resaltados.
+pub fn highlight(text: &str, matched: &[usize]) -> String {
+    let mut out = String::with_capacity(text.len() * 2);
+    let mut buf = [0u8; 4];
+    for (pos, c) in text.char_indices() {
+        let escaped = glib::markup_escape_text(c.encode_utf8(&mut buf));
+        if matched.binary_search(&pos).is_ok() {
+            out.push_str("<span weight=\"bold\" foreground=\"#89b4fa\">");
+            out.push_str(&escaped);
+            out.push_str("</span>");
+        } else {
+            out.push_str(&escaped);
+        }
+    }
+    out
+}
+
+/// Un trozo de `text` que empieza un poco antes de `pos`, para enseñar una
+/// coincidencia que cae fuera de la vista previa.
+pub fn snippet(text: &str, pos: usize, max_chars: usize) -> &str {
+    let start = text[..pos]
+        .char_indices()
+        .rev()
+        .take(20)
+        .last()
+        .map_or(pos, |(i, _)| i);
+    // Mejor empezar en una línea o palabra entera si está cerca
+    let start = text[start..pos]
+        .find(['\n', ' '])
+        .map_or(start, |i| start + i + 1);
+    let end = text[start..]
+        .char_indices()
+        .nth(max_chars)
+        .map_or(text.len(), |(i, _)| start + i);
+    &text[start..end]
+}
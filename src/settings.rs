@@ -0,0 +1,502 @@
+//! Ajustes de klipBored en `~/.config/klipBored/config.toml`.
+//!
+//! El fichero lleva un número de versión para poder migrarlo si cambia su
+//! estructura. Las versiones antiguas guardaban el atajo en `keybinding` y el
+//! resto en `settings` (`clave = valor`); se migran la primera vez.
+
+use crate::keybinding::{self, Action};
+use gtk::glib;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Versión actual de la estructura de `config.toml`.
+pub const VERSION: u32 = 1;
+
+/// Dónde dejar el contenido al recuperar una entrada del historial.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CopyTarget {
+    Clipboard,
+    Primary,
+    Both,
+}
+
+impl CopyTarget {
+    pub const ALL: [CopyTarget; 3] = [CopyTarget::Clipboard, CopyTarget::Primary, CopyTarget::Both];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            CopyTarget::Clipboard => "clipboard",
+            CopyTarget::Primary => "primary",
+            CopyTarget::Both => "both",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        CopyTarget::ALL.into_iter().find(|t| t.as_str() == s)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            CopyTarget::Clipboard => "Portapapeles",
+            CopyTarget::Primary => "Selección primaria",
+            CopyTarget::Both => "Ambos",
+        }
+    }
+}
+
+/// Qué hacer con un texto que parece un secreto (claves, tokens, tarjetas).
+/// Lo que los gestores de contraseñas marcan como secreto no se guarda nunca.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensitiveMode {
+    Skip,
+    Flag,
+}
+
+impl SensitiveMode {
+    pub const ALL: [SensitiveMode; 2] = [SensitiveMode::Skip, SensitiveMode::Flag];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            SensitiveMode::Skip => "skip",
+            SensitiveMode::Flag => "flag",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        SensitiveMode::ALL.into_iter().find(|m| m.as_str() == s)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            SensitiveMode::Skip => "No guardarlo",
+            SensitiveMode::Flag => "Guardarlo oculto",
+        }
+    }
+}
+
+/// Cuándo borrar el historial (salvo las fijadas) por sí solo.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClearSchedule {
+    Never,
+    Midnight,
+    Logout,
+}
+
+impl ClearSchedule {
+    pub const ALL: [ClearSchedule; 3] = [
+        ClearSchedule::Never,
+        ClearSchedule::Midnight,
+        ClearSchedule::Logout,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            ClearSchedule::Never => "never",
+            ClearSchedule::Midnight => "midnight",
+            ClearSchedule::Logout => "logout",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        ClearSchedule::ALL.into_iter().find(|c| c.as_str() == s)
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ClearSchedule::Never => "Nunca",
+            ClearSchedule::Midnight => "A medianoche",
+            ClearSchedule::Logout => "Al cerrar sesión",
+        }
+    }
+}
+
+/// Tema de la ventana.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Theme {
+    System,
+    Light,
+    Dark,
+}
+
+impl Theme {
+    pub const ALL: [Theme; 3] = [Theme::System, Theme::Light, Theme::Dark];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Theme::System => "El del sistema",
+            Theme::Light => "Claro",
+            Theme::Dark => "Oscuro",
+        }
+    }
+}
+
+/// Todos los ajustes, tal y como se guardan en `config.toml`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub version: u32,
+    /// Atajo que abre el panel; vacío hasta completar el asistente.
+    pub keybinding: String,
+    pub history: HistorySettings,
+    pub capture: CaptureSettings,
+    pub window: WindowSettings,
+    pub privacy: PrivacySettings,
+    /// Acciones de GNOME a las que se quitó el atajo para dárselo a
+    /// klipBored, con el que tenían.
+    pub overridden_bindings: Vec<Action>,
+}
+
+/// Límites y borrado automático del historial.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistorySettings {
+    /// Número máximo de entradas del historial.
+    pub max_entries: usize,
+    /// Tamaño máximo de todo el historial, en MB.
+    pub max_total_mb: u64,
+    /// Parte de `max_total_mb` que pueden ocupar las imágenes.
+    pub max_image_mb: u64,
+    /// Antigüedad máxima de cada tipo de entrada, en días; 0 es sin límite.
+    pub text_max_age_days: u64,
+    pub image_max_age_days: u64,
+    pub files_max_age_days: u64,
+    pub clear_schedule: ClearSchedule,
+}
+
+/// Qué se guarda en el historial.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptureSettings {
+    /// Guardar también la selección primaria.
+    pub primary: bool,
+}
+
+/// Cómo se comporta el panel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowSettings {
+    pub copy_target: CopyTarget,
+    /// Pulsar la combinación de pegar en la ventana anterior al recuperar.
+    pub auto_paste: bool,
+    pub hide_on_focus_loss: bool,
+    pub theme: Theme,
+}
+
+/// Qué hacer con el contenido que parece privado.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrivacySettings {
+    pub sensitive: SensitiveMode,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            keybinding: String::new(),
+            history: HistorySettings::default(),
+            capture: CaptureSettings::default(),
+            window: WindowSettings::default(),
+            privacy: PrivacySettings::default(),
+            overridden_bindings: Vec::new(),
+        }
+    }
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        Self {
+            max_entries: 200,
+            max_total_mb: 256,
+            max_image_mb: 128,
+            text_max_age_days: 0,
+            image_max_age_days: 0,
+            files_max_age_days: 0,
+            clear_schedule: ClearSchedule::Never,
+        }
+    }
+}
+
+impl Default for WindowSettings {
+    fn default() -> Self {
+        Self {
+            copy_target: CopyTarget::Clipboard,
+            auto_paste: false,
+            hide_on_focus_loss: true,
+            theme: Theme::System,
+        }
+    }
+}
+
+impl Default for PrivacySettings {
+    fn default() -> Self {
+        Self {
+            sensitive: SensitiveMode::Skip,
+        }
+    }
+}
+
+pub fn config_dir() -> PathBuf {
+    glib::user_config_dir().join("klipBored")
+}
+
+fn config_file() -> PathBuf {
+    config_dir().join("config.toml")
+}
+
+impl Settings {
+    /// Lee `config.toml`, o migra los ficheros antiguos si aún no existe.
+    /// Si tiene errores se usan los valores por defecto para lo que falle y
+    /// se devuelve además la descripción, para enseñarla en los ajustes.
+    pub fn load() -> (Self, Option<String>) {
+        let path = config_file();
+        let Ok(raw) = fs::read_to_string(&path) else {
+            return (Settings::from_legacy(), None);
+        };
+        match Settings::parse(&raw) {
+            Ok((mut settings, warnings)) => {
+                if settings.version < VERSION {
+                    settings.version = VERSION;
+                    settings.save();
+                }
+                (
+                    settings,
+                    (!warnings.is_empty()).then(|| warnings.join("\n")),
+                )
+            }
+            Err(e) => {
+                // Se guarda aparte para no perderlo al guardar los ajustes
+                let backup = path.with_extension("toml.bak");
+                let _ = fs::copy(&path, &backup);
+                let settings = Settings {
+                    keybinding: rescue_keybinding(&raw),
+                    ..Settings::default()
+                };
+                let error = format!(
+                    "config.toml no es válido, se usan los valores por defecto (copia en {}): {}",
+                    backup.display(),
+                    e
+                );
+                (settings, Some(error))
+            }
+        }
+    }
+
+    /// Interpreta un `config.toml`. Los valores fuera de rango se corrigen y
+    /// se devuelven como avisos; solo falla si el fichero no se entiende.
+    pub fn parse(raw: &str) -> Result<(Self, Vec<String>), String> {
+        let mut settings: Settings = toml::from_str(raw).map_err(|e| e.message().to_string())?;
+        let mut warnings = Vec::new();
+        if settings.version > VERSION {
+            warnings.push(format!(
+                "config.toml es de una versión más nueva de klipBored ({}); puede que se ignoren ajustes",
+                settings.version
+            ));
+        }
+        let history = &mut settings.history;
+        clamp(
+            &mut warnings,
+            "history.max_entries",
+            &mut history.max_entries,
+            10,
+            10000,
+        );
+        clamp(
+            &mut warnings,
+            "history.max_total_mb",
+            &mut history.max_total_mb,
+            16,
+            8192,
+        );
+        // Las imágenes cuentan dentro del total
+        clamp(
+            &mut warnings,
+            "history.max_image_mb",
+            &mut history.max_image_mb,
+            16,
+            history.max_total_mb,
+        );
+        for (key, days) in [
+            ("history.text_max_age_days", &mut history.text_max_age_days),
+            (
+                "history.image_max_age_days",
+                &mut history.image_max_age_days,
+            ),
+            (
+                "history.files_max_age_days",
+                &mut history.files_max_age_days,
+            ),
+        ] {
+            clamp(&mut warnings, key, days, 0, 3650);
+        }
+        Ok((settings, warnings))
+    }
+
+    pub fn save(&self) {
+        if let Err(e) = self.save_to(&config_file()) {
+            eprintln!("klipBored: no se pudieron guardar los ajustes: {}", e);
+        }
+    }
+
+    /// Escribe los ajustes en `path` (también para exportarlos).
+    pub fn save_to(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Los ajustes de las versiones sin `config.toml`. Los ficheros antiguos
+    /// se borran una vez migrados.
+    fn from_legacy() -> Self {
+        let mut settings = Settings::default();
+        let binding_file = config_dir().join("keybinding");
+        if let Ok(binding) = fs::read_to_string(&binding_file) {
+            settings.keybinding = binding.trim().to_string();
+            settings
+                .overridden_bindings
+                .extend(keybinding::legacy_override(&settings.keybinding));
+        }
+        let legacy = config_dir().join("settings");
+        let raw = fs::read_to_string(&legacy).unwrap_or_default();
+        for line in raw.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            let history = &mut settings.history;
+            match key.trim() {
+                "capture_primary" => settings.capture.primary = value == "true",
+                "copy_target" => {
+                    if let Some(t) = CopyTarget::parse(value) {
+                        settings.window.copy_target = t;
+                    }
+                }
+                "max_entries" => {
+                    if let Ok(n) = value.parse() {
+                        history.max_entries = n;
+                    }
+                }
+                "max_total_mb" => {
+                    if let Ok(n) = value.parse() {
+                        history.max_total_mb = n;
+                    }
+                }
+                "max_image_mb" => {
+                    if let Ok(n) = value.parse() {
+                        history.max_image_mb = n;
+                    }
+                }
+                "auto_paste" => settings.window.auto_paste = value == "true",
+                "text_max_age_days" => {
+                    if let Ok(n) = value.parse() {
+                        history.text_max_age_days = n;
+                    }
+                }
+                "image_max_age_days" => {
+                    if let Ok(n) = value.parse() {
+                        history.image_max_age_days = n;
+                    }
+                }
+                "files_max_age_days" => {
+                    if let Ok(n) = value.parse() {
+                        history.files_max_age_days = n;
+                    }
+                }
+                "clear_schedule" => {
+                    if let Some(c) = ClearSchedule::parse(value) {
+                        history.clear_schedule = c;
+                    }
+                }
+                "sensitive" => {
+                    if let Some(m) = SensitiveMode::parse(value) {
+                        settings.privacy.sensitive = m;
+                    }
+                }
+                _ => {}
+            }
+        }
+        if settings.save_to(&config_file()).is_ok() {
+            let _ = fs::remove_file(binding_file);
+            let _ = fs::remove_file(legacy);
+        }
+        settings
+    }
+}
+
+/// El atajo de un `config.toml` que no se puede leer entero, para no volver
+/// a pasar por el asistente.
+fn rescue_keybinding(raw: &str) -> String {
+    raw.parse::<toml::Table>()
+        .ok()
+        .and_then(|table| Some(table.get("keybinding")?.as_str()?.to_string()))
+        .unwrap_or_default()
+}
+
+fn clamp<T: PartialOrd + Copy + std::fmt::Display>(
+    warnings: &mut Vec<String>,
+    key: &str,
+    value: &mut T,
+    min: T,
+    max: T,
+) {
+    if *value < min || *value > max {
+        let fixed = if *value < min { min } else { max };
+        warnings.push(format!(
+            "{} = {} está fuera de rango ({}–{}); se usa {}",
+            key, value, min, max, fixed
+        ));
+        *value = fixed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_file_is_the_defaults() {
+        let (settings, warnings) = Settings::parse("").unwrap();
+        assert_eq!(settings, Settings::default());
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn out_of_range_values_are_clamped() {
+        let raw = "[history]\nmax_entries = 5\nmax_total_mb = 100000\ntext_max_age_days = 9999\n";
+        let (settings, warnings) = Settings::parse(raw).unwrap();
+        assert_eq!(settings.history.max_entries, 10);
+        assert_eq!(settings.history.max_total_mb, 8192);
+        assert_eq!(settings.history.text_max_age_days, 3650);
+        assert_eq!(warnings.len(), 3);
+    }
+
+    #[test]
+    fn image_quota_stays_within_the_total() {
+        let raw = "[history]\nmax_total_mb = 64\nmax_image_mb = 128\n";
+        let (settings, warnings) = Settings::parse(raw).unwrap();
+        assert_eq!(settings.history.max_image_mb, 64);
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn missing_keys_keep_their_defaults() {
+        let raw = "keybinding = \"<Super>v\"\n[window]\nauto_paste = true\n";
+        let (settings, _) = Settings::parse(raw).unwrap();
+        assert_eq!(settings.keybinding, "<Super>v");
+        assert!(settings.window.auto_paste);
+        assert_eq!(settings.history, HistorySettings::default());
+    }
+
+    #[test]
+    fn invalid_toml_is_an_error() {
+        assert!(Settings::parse("[history\nmax_entries = 5").is_err());
+        assert!(Settings::parse("[history]\nmax_entries = \"muchas\"").is_err());
+    }
+}
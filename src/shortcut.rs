@@ -0,0 +1,162 @@
+//! Atajos de teclado para abrir el panel.
+//!
+//! Se guardan como los entiende GTK (`<Super>v`, `<Control><Shift>v`, `F12`)
+//! y así se pasan a GNOME. En vez de escribirlos a mano, se graban pulsando
+//! la combinación en un botón.
+
+use gtk::prelude::*;
+use gtk::{gdk, glib};
+use std::cell::Cell;
+use std::rc::Rc;
+
+const RECORD_HINT: &str = "Pulsa aquí y luego tu atajo";
+
+/// Combinaciones que ya usan las aplicaciones o el sistema.
+const RESERVED: &[&str] = &[
+    "<Control>c",
+    "<Control>x",
+    "<Control>v",
+    "<Control>z",
+    "<Control>a",
+    "<Control>s",
+    "<Alt>Tab",
+    "<Alt>F4",
+    "<Super>l",
+    "<Control><Alt>Delete",
+];
+
+/// Comprueba que `accel` se pueda usar para abrir el panel.
+pub fn validate(accel: &str) -> Result<(), String> {
+    let (key, mods) = gtk::accelerator_parse(accel).ok_or("no es un atajo válido")?;
+    if !gtk::accelerator_valid(key, mods) {
+        return Err("esa tecla no sirve para un atajo".to_string());
+    }
+    // Una tecla sola (o con Mayúsculas) dejaría de escribirse
+    let typing = mods.difference(gdk::ModifierType::SHIFT_MASK).is_empty();
+    if typing && !is_function_key(key) {
+        return Err("necesita Ctrl, Alt o Super, salvo las teclas F1–F24".to_string());
+    }
+    let reserved = RESERVED
+        .iter()
+        .filter_map(|r| gtk::accelerator_parse(*r))
+        .any(|(k, m)| k == key.to_lower() && m == mods);
+    if reserved {
+        return Err(format!(
+            "{} ya lo usa el sistema o las aplicaciones",
+            label(accel)
+        ));
+    }
+    Ok(())
+}
+
+/// Cómo se enseña el atajo, en el idioma del escritorio.
+pub fn label(accel: &str) -> String {
+    match gtk::accelerator_parse(accel) {
+        Some((key, mods)) => gtk::accelerator_get_label(key, mods).to_string(),
+        None => accel.to_string(),
+    }
+}
+
+fn is_function_key(key: gdk::Key) -> bool {
+    key.name().is_some_and(|name| {
+        name.strip_prefix('F')
+            .and_then(|n| n.parse::<u8>().ok())
+            .is_some_and(|n| (1..=24).contains(&n))
+    })
+}
+
+fn is_modifier(key: gdk::Key) -> bool {
+    use gdk::Key;
+    matches!(
+        key,
+        Key::Shift_L
+            | Key::Shift_R
+            | Key::Control_L
+            | Key::Control_R
+            | Key::Alt_L
+            | Key::Alt_R
+            | Key::Meta_L
+            | Key::Meta_R
+            | Key::Super_L
+            | Key::Super_R
+            | Key::Hyper_L
+            | Key::Hyper_R
+            | Key::ISO_Level3_Shift
+            | Key::Caps_Lock
+            | Key::Num_Lock
+    )
+}
+
+/// Botón que graba el siguiente atajo que se pulse sobre él. Entrega el
+/// atajo, o por qué no vale, a `on_record`. Escape cancela.
+pub fn recorder(on_record: impl Fn(Result<String, String>) + 'static) -> gtk::Button {
+    let button = gtk::Button::with_label(RECORD_HINT);
+    button.add_css_class("wizard-shortcut-option");
+    button.add_css_class("shortcut-recorder");
+    let recording = Rc::new(Cell::new(false));
+
+    let stop = {
+        let recording = recording.clone();
+        move |button: &gtk::Button, label: &str| {
+            recording.set(false);
+            button.remove_css_class("recording");
+            button.set_label(label);
+        }
+    };
+
+    let rec = recording.clone();
+    button.connect_clicked(move |button| {
+        rec.set(true);
+        button.add_css_class("recording");
+        button.set_label("Pulsa la combinación… (Esc cancela)");
+    });
+
+    // En captura, para que Espacio o Intro no vuelvan a pulsar el botón
+    let keys = gtk::EventControllerKey::new();
+    keys.set_propagation_phase(gtk::PropagationPhase::Capture);
+    let rec = recording.clone();
+    let stop_keys = stop.clone();
+    keys.connect_key_pressed(move |keys, key, keycode, state| {
+        if !rec.get() {
+            return glib::Propagation::Proceed;
+        }
+        let Some(button) = keys.widget().and_downcast::<gtk::Button>() else {
+            return glib::Propagation::Proceed;
+        };
+        if is_modifier(key) {
+            return glib::Propagation::Stop;
+        }
+        let mods = state & gtk::accelerator_get_default_mod_mask();
+        if key == gdk::Key::Escape && mods.is_empty() {
+            stop_keys(&button, RECORD_HINT);
+            return glib::Propagation::Stop;
+        }
+        // La tecla sin Mayús: con Mayús+2 llega `@` o `"`, según la
+        // distribución, pero el atajo es <Shift>2
+        let key = button
+            .display()
+            .translate_key(
+                keycode,
+                state - gdk::ModifierType::SHIFT_MASK,
+                keys.group() as i32,
+            )
+            .map_or(key, |(key, ..)| key);
+        let accel = gtk::accelerator_name(key.to_lower(), mods).to_string();
+        stop_keys(&button, &label(&accel));
+        on_record(validate(&accel).map(|()| accel));
+        glib::Propagation::Stop
+    });
+    button.add_controller(keys);
+
+    let focus = gtk::EventControllerFocus::new();
+    focus.connect_leave(move |focus| {
+        if recording.get() {
+            if let Some(button) = focus.widget().and_downcast::<gtk::Button>() {
+                stop(&button, RECORD_HINT);
+            }
+        }
+    });
+    button.add_controller(focus);
+
+    button
+}
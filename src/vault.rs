@@ -0,0 +1,375 @@
+//! Cifrado del historial en disco.
+//!
+//! Todo lo que escribe `HistoryStore` pasa por un `Cipher`
+//! (ChaCha20-Poly1305). La clave se genera la primera vez y se guarda en el
+//! llavero del escritorio (gnome-keyring, KWallet...) a través de la API
+//! Secret Service. Sin llavero, la clave se deriva de una contraseña que el
+//! usuario escribe al arrancar.
+//!
+//! Las llamadas al llavero son bloqueantes y pueden mostrar un diálogo para
+//! desbloquearlo, así que deben hacerse desde un hilo propio.
+
+use crate::history::data_dir;
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use gtk::gio;
+use gtk::glib::{self, variant::ObjectPath, variant::ToVariant};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// Cabecera de los ficheros cifrados; lo que no la lleva es de antes del cifrado
+const MAGIC: &[u8] = b"klipBored\x01";
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+const SALT_FILE: &str = "salt";
+
+const SECRETS: &str = "org.freedesktop.secrets";
+const SERVICE_PATH: &str = "/org/freedesktop/secrets";
+const DEFAULT_COLLECTION: &str = "/org/freedesktop/secrets/aliases/default";
+const SERVICE: &str = "org.freedesktop.Secret.Service";
+const COLLECTION: &str = "org.freedesktop.Secret.Collection";
+const ITEM: &str = "org.freedesktop.Secret.Item";
+const PROMPT: &str = "org.freedesktop.Secret.Prompt";
+// El diálogo de desbloqueo espera al usuario
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Clave con la que se cifra y descifra el historial.
+#[derive(Clone)]
+pub struct Cipher(ChaCha20Poly1305);
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Cipher(..)")
+    }
+}
+
+impl Cipher {
+    fn from_key(key: &[u8]) -> Self {
+        Cipher(ChaCha20Poly1305::new_from_slice(key).expect("clave de 32 bytes"))
+    }
+
+    fn generate() -> (Self, Vec<u8>) {
+        let key = ChaCha20Poly1305::generate_key(&mut OsRng).to_vec();
+        (Cipher::from_key(&key), key)
+    }
+
+    /// Cabecera, nonce aleatorio y texto cifrado.
+    pub fn seal(&self, plain: &[u8]) -> Vec<u8> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self
+            .0
+            .encrypt(&nonce, plain)
+            .expect("cifrar en memoria no falla");
+        [MAGIC, nonce.as_slice(), &sealed].concat()
+    }
+
+    /// Descifra lo escrito con `seal`. Lo que no está cifrado (historial de
+    /// una versión anterior) se devuelve tal cual.
+    pub fn open(&self, data: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let Some(rest) = data.strip_prefix(MAGIC) else {
+            return Ok(data);
+        };
+        if rest.len() < NONCE_LEN {
+            return Err("fichero cifrado truncado".into());
+        }
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        self.0
+            .decrypt(Nonce::from_slice(nonce), sealed)
+            .map_err(|_| "no se pudo descifrar (clave incorrecta)".into())
+    }
+}
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+fn salt_file() -> std::path::PathBuf {
+    data_dir().join(SALT_FILE)
+}
+
+/// Si el historial se cifró con una contraseña en vez de con el llavero.
+pub fn uses_passphrase() -> bool {
+    salt_file().exists()
+}
+
+/// Deriva la clave de la contraseña con la sal guardada.
+pub fn from_passphrase(passphrase: &str) -> Result<Cipher, Box<dyn Error>> {
+    let salt = fs::read(salt_file())?;
+    derive(passphrase, &salt)
+}
+
+fn derive(passphrase: &str, salt: &[u8]) -> Result<Cipher, Box<dyn Error>> {
+    let mut key = [0; KEY_LEN];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| e.to_string())?;
+    Ok(Cipher::from_key(&key))
+}
+
+/// Una clave recién generada que todavía no se ha guardado. Al cambiar de
+/// clave se guarda después de volver a cifrar el historial: si algo falla
+/// a medias, sigue valiendo la de antes.
+pub enum NewKey {
+    /// Sal de una contraseña nueva.
+    Salt(Vec<u8>),
+    /// Clave para el llavero.
+    Keyring(Vec<u8>),
+}
+
+impl std::fmt::Debug for NewKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NewKey::Salt(_) => f.write_str("NewKey::Salt(..)"),
+            NewKey::Keyring(_) => f.write_str("NewKey::Keyring(..)"),
+        }
+    }
+}
+
+impl NewKey {
+    /// Sustituye la clave guardada por esta. Con el llavero es bloqueante.
+    pub fn save(self) -> Result<(), Box<dyn Error>> {
+        match self {
+            NewKey::Salt(salt) => {
+                fs::create_dir_all(data_dir())?;
+                fs::write(salt_file(), salt)?;
+                Ok(())
+            }
+            NewKey::Keyring(key) => Keyring::with(|keyring| keyring.store(&key)),
+        }
+    }
+}
+
+/// Deriva la clave de una contraseña nueva, con otra sal.
+pub fn new_passphrase(passphrase: &str) -> Result<(Cipher, NewKey), Box<dyn Error>> {
+    let mut salt = vec![0; 16];
+    OsRng.fill_bytes(&mut salt);
+    let cipher = derive(passphrase, &salt)?;
+    Ok((cipher, NewKey::Salt(salt)))
+}
+
+/// La clave guardada en el llavero; si no hay ninguna, se genera y se guarda.
+pub fn from_keyring() -> Result<Cipher, Box<dyn Error>> {
+    Keyring::with(|keyring| match keyring.lookup()? {
+        Some(key) if key.len() == KEY_LEN => Ok(Cipher::from_key(&key)),
+        _ => {
+            let (cipher, key) = Cipher::generate();
+            keyring.store(&key)?;
+            Ok(cipher)
+        }
+    })
+}
+
+/// Genera una clave nueva para sustituir la del llavero.
+pub fn new_keyring_key() -> (Cipher, NewKey) {
+    let (cipher, key) = Cipher::generate();
+    (cipher, NewKey::Keyring(key))
+}
+
+/// Olvida la clave, esté en el llavero o derivada de una contraseña.
+pub fn forget() {
+    let _ = fs::remove_file(salt_file());
+    if let Err(e) = Keyring::with(|keyring| keyring.delete()) {
+        eprintln!("klipBored: no se pudo borrar la clave del llavero: {}", e);
+    }
+}
+
+fn attributes() -> HashMap<String, String> {
+    HashMap::from([
+        (
+            "application".to_string(),
+            "io.github.klipbored.app".to_string(),
+        ),
+        ("purpose".to_string(), "history-key".to_string()),
+    ])
+}
+
+/// Sesión con el servicio del llavero, con transporte "plain": la clave
+/// viaja sin cifrar por el bus de sesión, como en libsecret.
+struct Keyring {
+    conn: gio::DBusConnection,
+    ctx: glib::MainContext,
+    session: ObjectPath,
+}
+
+impl Keyring {
+    fn with<R>(f: impl FnOnce(&Keyring) -> Result<R, Box<dyn Error>>) -> Result<R, Box<dyn Error>> {
+        let ctx = glib::MainContext::new();
+        ctx.with_thread_default(|| {
+            let conn = gio::bus_get_sync(gio::BusType::Session, gio::Cancellable::NONE)?;
+            let reply = call(
+                &conn,
+                SERVICE_PATH,
+                SERVICE,
+                "OpenSession",
+                &("plain", "".to_variant()).to_variant(),
+            )?;
+            let (_, session): (glib::Variant, ObjectPath) =
+                reply.get().ok_or("respuesta inesperada del llavero")?;
+            let keyring = Keyring {
+                conn,
+                ctx: ctx.clone(),
+                session,
+            };
+            let res = f(&keyring);
+            let _ = keyring.call(
+                keyring.session.as_str(),
+                "org.freedesktop.Secret.Session",
+                "Close",
+                &().to_variant(),
+            );
+            res
+        })?
+    }
+
+    fn call(
+        &self,
+        path: &str,
+        interface: &str,
+        method: &str,
+        args: &glib::Variant,
+    ) -> Result<glib::Variant, glib::Error> {
+        call(&self.conn, path, interface, method, args)
+    }
+
+    /// Nuestras entradas del llavero: desbloqueadas y bloqueadas.
+    fn search(&self) -> Result<(Vec<ObjectPath>, Vec<ObjectPath>), Box<dyn Error>> {
+        let reply = self.call(
+            SERVICE_PATH,
+            SERVICE,
+            "SearchItems",
+            &(attributes(),).to_variant(),
+        )?;
+        Ok(reply.get().ok_or("respuesta inesperada del llavero")?)
+    }
+
+    fn lookup(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let (unlocked, locked) = self.search()?;
+        let item = match (unlocked.first(), locked.first()) {
+            (Some(item), _) => item.clone(),
+            (None, Some(item)) => {
+                self.unlock(vec![item.clone()])?;
+                item.clone()
+            }
+            (None, None) => return Ok(None),
+        };
+        let reply = self.call(
+            item.as_str(),
+            ITEM,
+            "GetSecret",
+            &(self.session.clone(),).to_variant(),
+        )?;
+        let ((_, _, value, _),): ((ObjectPath, Vec<u8>, Vec<u8>, String),) =
+            reply.get().ok_or("respuesta inesperada del llavero")?;
+        Ok(Some(value))
+    }
+
+    fn store(&self, key: &[u8]) -> Result<(), Box<dyn Error>> {
+        // La colección por defecto puede estar bloqueada
+        self.unlock(vec![ObjectPath::try_from(DEFAULT_COLLECTION)?])?;
+        let properties = crate::portal::options(&[
+            (
+                "org.freedesktop.Secret.Item.Label",
+                "klipBored".to_variant(),
+            ),
+            (
+                "org.freedesktop.Secret.Item.Attributes",
+                attributes().to_variant(),
+            ),
+        ]);
+        let secret = (
+            self.session.clone(),
+            Vec::<u8>::new(),
+            key.to_vec(),
+            "application/octet-stream".to_string(),
+        );
+        let args =
+            glib::Variant::tuple_from_iter([properties, secret.to_variant(), true.to_variant()]);
+        let reply = self.call(DEFAULT_COLLECTION, COLLECTION, "CreateItem", &args)?;
+        let (_, prompt): (ObjectPath, ObjectPath) =
+            reply.get().ok_or("respuesta inesperada del llavero")?;
+        self.prompt(prompt)
+    }
+
+    fn delete(&self) -> Result<(), Box<dyn Error>> {
+        let (unlocked, locked) = self.search()?;
+        if !locked.is_empty() {
+            self.unlock(locked.clone())?;
+        }
+        for item in unlocked.iter().chain(&locked) {
+            let reply = self.call(item.as_str(), ITEM, "Delete", &().to_variant())?;
+            let (prompt,): (ObjectPath,) = reply.get().ok_or("respuesta inesperada del llavero")?;
+            self.prompt(prompt)?;
+        }
+        Ok(())
+    }
+
+    fn unlock(&self, objects: Vec<ObjectPath>) -> Result<(), Box<dyn Error>> {
+        let reply = self.call(SERVICE_PATH, SERVICE, "Unlock", &(objects,).to_variant())?;
+        let (_, prompt): (Vec<ObjectPath>, ObjectPath) =
+            reply.get().ok_or("respuesta inesperada del llavero")?;
+        self.prompt(prompt)
+    }
+
+    /// Muestra el diálogo del llavero, si hace falta ("/" es que no), y
+    /// espera a que el usuario lo cierre.
+    fn prompt(&self, prompt: ObjectPath) -> Result<(), Box<dyn Error>> {
+        if prompt.as_str() == "/" {
+            return Ok(());
+        }
+        let dismissed: Rc<Cell<Option<bool>>> = Rc::default();
+        let dismissed_cb = dismissed.clone();
+        let _subscription = self.conn.subscribe_to_signal(
+            Some(SECRETS),
+            Some(PROMPT),
+            Some("Completed"),
+            Some(prompt.as_str()),
+            None,
+            gio::DBusSignalFlags::NONE,
+            move |signal| {
+                let completed: Option<(bool, glib::Variant)> = signal.parameters.get();
+                dismissed_cb.set(completed.map(|(dismissed, _)| dismissed));
+            },
+        );
+        self.call(prompt.as_str(), PROMPT, "Prompt", &("",).to_variant())?;
+
+        let deadline = Instant::now() + PROMPT_TIMEOUT;
+        while dismissed.get().is_none() {
+            if Instant::now() >= deadline {
+                return Err("el llavero no respondió".into());
+            }
+            if !self.ctx.iteration(false) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+        match dismissed.get() {
+            Some(false) => Ok(()),
+            _ => Err("desbloqueo del llavero cancelado".into()),
+        }
+    }
+}
+
+fn call(
+    conn: &gio::DBusConnection,
+    path: &str,
+    interface: &str,
+    method: &str,
+    args: &glib::Variant,
+) -> Result<glib::Variant, glib::Error> {
+    conn.call_sync(
+        Some(SECRETS),
+        path,
+        interface,
+        method,
+        Some(args),
+        None,
+        gio::DBusCallFlags::NONE,
+        -1,
+        gio::Cancellable::NONE,
+    )
+}
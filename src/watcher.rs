@@ -0,0 +1,240 @@
+//! Detección de cambios en el portapapeles.
+//!
+//! En lugar de leer el portapapeles cada pocos milisegundos nos suscribimos
+//! a los avisos del servidor gráfico: el protocolo data-control (ext o wlr)
+//! en Wayland y XFixes en X11. Cada aviso se notifica desde un hilo propio;
+//! quien llama decide qué hacer (normalmente, mandar un mensaje a la UI).
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+/// Selección de la que procede un cambio (y una entrada del historial).
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Selection {
+    #[default]
+    Clipboard,
+    Primary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    DataControl,
+    XFixes,
+    Polling,
+}
+
+/// Arranca el mejor vigilante disponible. Si no hay ninguno devuelve
+/// `Backend::Polling` y es cosa de quien llama sondear el portapapeles.
+pub fn spawn<F>(notify: F) -> Backend
+where
+    F: Fn(Selection) + Clone + Send + 'static,
+{
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        match wayland::spawn(notify.clone()) {
+            Ok(()) => return Backend::DataControl,
+            Err(e) => eprintln!("klipBored: data-control no disponible: {}", e),
+        }
+    }
+    // En Wayland sin data-control probamos XFixes sobre XWayland, que refleja
+    // el portapapeles de las aplicaciones nativas en la mayoría de compositores.
+    if std::env::var_os("DISPLAY").is_some() {
+        match x11::spawn(notify) {
+            Ok(()) => return Backend::XFixes,
+            Err(e) => eprintln!("klipBored: XFixes no disponible: {}", e),
+        }
+    }
+    Backend::Polling
+}
+
+mod x11 {
+    use super::{Error, Selection};
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xfixes::{ConnectionExt as _, SelectionEventMask};
+    use x11rb::protocol::xproto::{AtomEnum, ConnectionExt as _, CreateWindowAux, WindowClass};
+    use x11rb::protocol::Event;
+    use x11rb::COPY_DEPTH_FROM_PARENT;
+
+    pub fn spawn<F>(notify: F) -> Result<(), Box<dyn Error>>
+    where
+        F: Fn(Selection) + Send + 'static,
+    {
+        let (conn, screen_num) = x11rb::connect(None)?;
+        conn.xfixes_query_version(5, 0)?.reply()?;
+
+        let screen = &conn.setup().roots[screen_num];
+        let win = conn.generate_id()?;
+        conn.create_window(
+            COPY_DEPTH_FROM_PARENT,
+            win,
+            screen.root,
+            0,
+            0,
+            1,
+            1,
+            0,
+            WindowClass::INPUT_ONLY,
+            x11rb::NONE,
+            &CreateWindowAux::new(),
+        )?;
+
+        let clipboard = conn.intern_atom(false, b"CLIPBOARD")?.reply()?.atom;
+        let primary = u32::from(AtomEnum::PRIMARY);
+        let mask = SelectionEventMask::SET_SELECTION_OWNER
+            | SelectionEventMask::SELECTION_WINDOW_DESTROY
+            | SelectionEventMask::SELECTION_CLIENT_CLOSE;
+        conn.xfixes_select_selection_input(win, clipboard, mask)?;
+        conn.xfixes_select_selection_input(win, primary, mask)?;
+        conn.flush()?;
+
+        std::thread::spawn(move || loop {
+            match conn.wait_for_event() {
+                Ok(Event::XfixesSelectionNotify(ev)) if ev.selection == clipboard => {
+                    notify(Selection::Clipboard)
+                }
+                Ok(Event::XfixesSelectionNotify(ev)) if ev.selection == primary => {
+                    notify(Selection::Primary)
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("klipBored: conexión X11 perdida: {}", e);
+                    break;
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+mod wayland {
+    use super::{Error, Selection};
+    use wayland_client::globals::{registry_queue_init, GlobalListContents};
+    use wayland_client::protocol::{wl_registry, wl_seat::WlSeat};
+    use wayland_client::{
+        delegate_noop, event_created_child, Connection, Dispatch, EventQueue, QueueHandle,
+    };
+    use wayland_protocols::ext::data_control::v1::client::{
+        ext_data_control_device_v1::{self, ExtDataControlDeviceV1},
+        ext_data_control_manager_v1::ExtDataControlManagerV1,
+        ext_data_control_offer_v1::ExtDataControlOfferV1,
+    };
+    use wayland_protocols_wlr::data_control::v1::client::{
+        zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
+        zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+        zwlr_data_control_offer_v1::ZwlrDataControlOfferV1,
+    };
+
+    struct State {
+        notify: Box<dyn Fn(Selection) + Send>,
+        ext_offers: [Option<ExtDataControlOfferV1>; 2],
+        wlr_offers: [Option<ZwlrDataControlOfferV1>; 2],
+    }
+
+    pub fn spawn<F>(notify: F) -> Result<(), Box<dyn Error>>
+    where
+        F: Fn(Selection) + Send + 'static,
+    {
+        let conn = Connection::connect_to_env()?;
+        let (globals, mut queue): (_, EventQueue<State>) = registry_queue_init(&conn)?;
+        let qh = queue.handle();
+
+        let seat: WlSeat = globals.bind(&qh, 1..=8, ())?;
+        // ext-data-control es el protocolo estandarizado; wlr queda para
+        // compositores que todavía no lo implementan.
+        if let Ok(manager) = globals.bind::<ExtDataControlManagerV1, _, _>(&qh, 1..=1, ()) {
+            manager.get_data_device(&seat, &qh, ());
+        } else {
+            let manager: ZwlrDataControlManagerV1 = globals.bind(&qh, 1..=2, ())?;
+            manager.get_data_device(&seat, &qh, ());
+        }
+
+        let mut state = State {
+            notify: Box::new(notify),
+            ext_offers: [None, None],
+            wlr_offers: [None, None],
+        };
+        // Procesa los eventos iniciales aquí para detectar errores de protocolo
+        // antes de dar el vigilante por bueno.
+        queue.roundtrip(&mut state)?;
+
+        std::thread::spawn(move || loop {
+            if let Err(e) = queue.blocking_dispatch(&mut state) {
+                eprintln!("klipBored: conexión Wayland perdida: {}", e);
+                break;
+            }
+        });
+        Ok(())
+    }
+
+    impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
+        fn event(
+            _: &mut Self,
+            _: &wl_registry::WlRegistry,
+            _: wl_registry::Event,
+            _: &GlobalListContents,
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    delegate_noop!(State: ignore WlSeat);
+    delegate_noop!(State: ExtDataControlManagerV1);
+    delegate_noop!(State: ZwlrDataControlManagerV1);
+    delegate_noop!(State: ignore ExtDataControlOfferV1);
+    delegate_noop!(State: ignore ZwlrDataControlOfferV1);
+
+    impl Dispatch<ExtDataControlDeviceV1, ()> for State {
+        fn event(
+            state: &mut Self,
+            _: &ExtDataControlDeviceV1,
+            event: ext_data_control_device_v1::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+            let (selection, id) = match event {
+                ext_data_control_device_v1::Event::Selection { id } => (Selection::Clipboard, id),
+                ext_data_control_device_v1::Event::PrimarySelection { id } => {
+                    (Selection::Primary, id)
+                }
+                _ => return,
+            };
+            if let Some(old) = std::mem::replace(&mut state.ext_offers[selection as usize], id) {
+                old.destroy();
+            }
+            (state.notify)(selection);
+        }
+
+        event_created_child!(State, ExtDataControlDeviceV1, [
+            ext_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ExtDataControlOfferV1, ()),
+        ]);
+    }
+
+    impl Dispatch<ZwlrDataControlDeviceV1, ()> for State {
+        fn event(
+            state: &mut Self,
+            _: &ZwlrDataControlDeviceV1,
+            event: zwlr_data_control_device_v1::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+            let (selection, id) = match event {
+                zwlr_data_control_device_v1::Event::Selection { id } => (Selection::Clipboard, id),
+                zwlr_data_control_device_v1::Event::PrimarySelection { id } => {
+                    (Selection::Primary, id)
+                }
+                _ => return,
+            };
+            if let Some(old) = std::mem::replace(&mut state.wlr_offers[selection as usize], id) {
+                old.destroy();
+            }
+            (state.notify)(selection);
+        }
+
+        event_created_child!(State, ZwlrDataControlDeviceV1, [
+            zwlr_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ZwlrDataControlOfferV1, ()),
+        ]);
+    }
+}